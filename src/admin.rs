@@ -0,0 +1,178 @@
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hyper::header::ContentType;
+use hyper::method::Method;
+use hyper::server::{Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use discord::model::{ChannelId, ServerId};
+use serde_json::Value;
+use configuration::Configuration;
+use error::Error;
+use shard::plugs::configuration::{module, setting};
+use store::{Record, Scope, ScopeKind, Store};
+
+/// The result of an admin request: the status, and the JSON body to send with it.
+type Reply = (StatusCode, Value);
+
+fn reply(status: StatusCode, message: &str) -> Reply {
+    (status, json!({ "error": message }))
+}
+
+/// Starts the admin API listener, if it is enabled.  Every request must carry the configured
+/// token as `Authorization: Bearer <token>`.  The API mirrors the `configure module.*` and
+/// `configure setting.*` commands:
+///
+/// - `GET /servers/{server}/modules/{module}`
+/// - `POST /servers/{server}/modules/{module}/enable`
+/// - `POST /servers/{server}/modules/{module}/disable`
+/// - `DELETE /servers/{server}/modules/{module}`, to follow the default (or, for a channel,
+///   the server) again
+/// - `GET /servers/{server}/settings/{setting}`
+/// - `PUT /servers/{server}/settings/{setting}` with the value as the body
+/// - `POST /servers/{server}/settings/{setting}/push` with the value as the body
+/// - `DELETE /servers/{server}/settings/{setting}`
+///
+/// The settings routes also work for a single channel or user, under
+/// `/servers/{server}/channels/{channel}/settings/{setting}` and
+/// `/servers/{server}/users/{user}/settings/{setting}`; the module routes for a single
+/// channel, under `/servers/{server}/channels/{channel}/modules/{module}`.
+///
+/// Since shards read modules and settings from the store as they need them, changes take
+/// effect immediately.  They are recorded in the server's history, as made by `admin.user`.
+/// The listener runs until the returned value is dropped.
+pub fn serve(config: &Configuration) -> Result<Option<Listening>, Error> {
+    if !config.admin().enabled { return Ok(None); }
+    if config.admin().token.is_empty() {
+        warn!("The admin API is enabled, but has no token; refusing to serve it.");
+        return Ok(None);
+    }
+
+    info!("Serving the admin API on {}...", config.admin().bind);
+    let token = format!("Bearer {}", config.admin().token);
+    let actor = config.admin().user;
+    let store = Store::from(&config)?;
+    let server = Server::http(&config.admin().bind[..])?;
+    let listening = server.handle(move |mut request: Request, mut response: Response| {
+        let (status, body) = if !authorized(&request, &token) {
+            reply(StatusCode::Unauthorized, "missing or incorrect token")
+        } else {
+            handle(&mut request, actor, &store).unwrap_or_else(|e| {
+                error!("Admin API request failed: {:?}", e);
+                reply(StatusCode::InternalServerError, &format!("{}", e))
+            })
+        };
+
+        *response.status_mut() = status;
+        response.headers_mut().set(ContentType::json());
+        let _ = response.send(body.to_string().as_bytes());
+    })?;
+    Ok(Some(listening))
+}
+
+fn authorized(request: &Request, token: &str) -> bool {
+    let given = match request.headers.get_raw("Authorization") {
+        Some(values) if values.len() == 1 => &values[0][..],
+        _ => return false
+    };
+    // Compare every byte, so the time taken doesn't depend on where the first difference is.
+    given.len() == token.len() &&
+        given.iter().zip(token.as_bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn handle(request: &mut Request, actor: u64, store: &Store) -> Result<Reply, Error> {
+    let path = match request.uri {
+        RequestUri::AbsolutePath(ref path) => path.split('?').next().unwrap_or("").to_owned(),
+        _ => return Ok(reply(StatusCode::BadRequest, "unsupported request uri"))
+    };
+    let segments = path.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>();
+    let mut body = String::new();
+    request.read_to_string(&mut body)?;
+
+    let server = match segments.get(1).map(|s| s.parse::<u64>()) {
+        Some(Ok(server)) if segments[0] == "servers" => ServerId(server),
+        _ => return Ok(reply(StatusCode::NotFound, "not found"))
+    };
+
+    // A channel or user scope moves the rest of the path along by two segments.
+    let (scope, rest) = match (segments.get(2).map(|s| *s), segments.get(3).map(|s| s.parse::<u64>())) {
+        (Some("channels"), Some(Ok(channel))) => (Scope::Channel(channel), &segments[4..]),
+        (Some("users"), Some(Ok(user))) => (Scope::User(user), &segments[4..]),
+        _ => (Scope::Server, &segments[2..])
+    };
+    let action = rest.get(2).map(|s| *s);
+    match (rest.get(0).map(|s| *s), rest.get(1)) {
+        (Some("modules"), Some(name)) if rest.len() <= 3 && scope.kind() != ScopeKind::User =>
+            handle_module(&request.method, server, scope, name, action, actor, store),
+        (Some("settings"), Some(name)) if rest.len() <= 3 =>
+            handle_setting(&request.method, server, scope, name, action, body.trim(), actor, store),
+        _ => Ok(reply(StatusCode::NotFound, "not found"))
+    }
+}
+
+/// Records a change made through the API in the server's history, as the `configure` plug
+/// does.  The change has already been made, so a failure here is only logged.
+fn record(server: ServerId, actor: u64, action: &str, name: &str, scope: Scope,
+          old: Option<String>, new: Option<String>, store: &Store) {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let record = Record { time, actor, action: action.to_owned(), name: name.to_owned(), scope: Some(scope), old, new };
+    if let Err(e) = store.history_record(server.0, &record) {
+        warn!("Could not record {:?} in the history of server {}: {:?}", record, server, e);
+    }
+}
+
+fn handle_module(method: &Method, server: ServerId, scope: Scope, name: &str, action: Option<&str>, actor: u64, store: &Store) -> Result<Reply, Error> {
+    let module = match module::find(name) {
+        Some(module) => module,
+        None => return Ok(reply(StatusCode::NotFound, "no such module"))
+    };
+    let stored = || store.module_is_enabled(server.0, scope, module.name()).map(|flag| flag.map(module::state));
+
+    let old = stored()?;
+    let change = match (method, action) {
+        (&Method::Get, None) => None,
+        (&Method::Post, Some("enable")) => { module.enable(server, scope, store)?; Some("module.enable") },
+        (&Method::Post, Some("disable")) => { module.disable(server, scope, store)?; Some("module.disable") },
+        (&Method::Delete, None) => { module.inherit(server, scope, store)?; Some("module.inherit") },
+        _ => return Ok(reply(StatusCode::MethodNotAllowed, "unsupported method"))
+    };
+    if let Some(change) = change { record(server, actor, change, module.name(), scope, old, stored()?, store); }
+
+    let channel = match scope { Scope::Channel(channel) => Some(ChannelId(channel)), _ => None };
+    let enabled = module.is_enabled_in(server, channel, store)?;
+    Ok((StatusCode::Ok, json!({ "server": server.0, "scope": scope, "module": module.name(), "enabled": enabled })))
+}
+
+fn handle_setting(method: &Method, server: ServerId, scope: Scope, name: &str, action: Option<&str>, value: &str, actor: u64, store: &Store) -> Result<Reply, Error> {
+    let setting = match setting::find(name) {
+        Some(setting) => setting,
+        None => return Ok(reply(StatusCode::NotFound, "no such setting"))
+    };
+    if !setting.allows(scope) {
+        return Ok(reply(StatusCode::UnprocessableEntity, &format!("setting can't be set per {:?}", scope.kind())));
+    }
+
+    let old = setting.get(server, scope, store)?;
+    let (success, change) = match (method, action) {
+        (&Method::Get, None) => (true, None),
+        (&Method::Put, None) => (setting.set(server, scope, value, store)?, Some("setting.set")),
+        (&Method::Post, Some("push")) => match setting.push(server, scope, value, store)? {
+            setting::Pushed::Done => (true, Some("setting.push")),
+            setting::Pushed::WrongKind => (false, None),
+            setting::Pushed::Corrupt(_) => return Ok(reply(StatusCode::Conflict, "the stored value is invalid; clear it first"))
+        },
+        (&Method::Delete, None) => { setting.clear(server, scope, store)?; (true, Some("setting.clear")) },
+        _ => return Ok(reply(StatusCode::MethodNotAllowed, "unsupported method"))
+    };
+
+    if !success { return Ok(reply(StatusCode::UnprocessableEntity, "incorrect format for setting value")); }
+    let value = setting.get(server, scope, store)?;
+    if let Some(change) = change { record(server, actor, change, setting.name(), scope, old, value.clone(), store); }
+    let mut body = json!({ "server": server.0, "setting": setting.name(), "value": value });
+    match scope {
+        Scope::Channel(channel) => { body["channel"] = json!(channel); },
+        Scope::User(user) => { body["user"] = json!(user); },
+        Scope::Server => {}
+    }
+    Ok((StatusCode::Ok, body))
+}
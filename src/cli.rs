@@ -0,0 +1,252 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use clap::{App as Application, Arg as Argument, ArgMatches, SubCommand};
+use discord::model::{ChannelId, ServerId};
+use configuration::Configuration;
+use shell;
+use error::Error;
+use shard::plugs::configuration::{module, setting};
+use store::{Export, ImportMode, Scope, Store};
+
+/// The subcommands, which run instead of the bot.  The administration subcommands go straight
+/// to the store, so they work whether or not the bot is running; since shards read modules and
+/// settings from the store as they need them, changes take effect immediately either way.
+pub fn subcommands<'a>() -> Vec<Application<'a, 'a>> {
+    let server = || Argument::with_name("server").required(true).help("the server id");
+    vec![
+        SubCommand::with_name("module").about("checks, enables, or disables a module for a server")
+            .arg(Argument::with_name("action").required(true).possible_values(&["enable", "disable", "inherit", "status"]))
+            .arg(server())
+            .arg(Argument::with_name("module").required(true).help("the module name, e.g. admin.log"))
+            .arg(Argument::with_name("channel").long("channel").value_name("ID").takes_value(true)
+                .help("the module's flag for a channel, rather than the whole server")),
+        SubCommand::with_name("setting").about("reads or changes a setting for a server")
+            .arg(Argument::with_name("action").required(true).possible_values(&["get", "set", "push", "clear"]))
+            .arg(server())
+            .arg(Argument::with_name("setting").required(true).help("the setting name, e.g. comfort.join.channel"))
+            .arg(Argument::with_name("value").multiple(true)
+                .required_ifs(&[("action", "set"), ("action", "push")])
+                .help("the value, for set and push"))
+            .arg(Argument::with_name("channel").long("channel").value_name("ID").takes_value(true)
+                .conflicts_with("user").help("the setting for a channel, rather than the whole server"))
+            .arg(Argument::with_name("user").long("user").value_name("ID").takes_value(true)
+                .help("the setting for a user, rather than the whole server")),
+        SubCommand::with_name("export").about("writes every module flag and setting of a server as JSON")
+            .arg(server())
+            .arg(Argument::with_name("output").short("o").long("output").value_name("FILE").takes_value(true)
+                .help("writes to the given file, rather than standard output")),
+        SubCommand::with_name("import").about("applies an export to a server")
+            .arg(server())
+            .arg(Argument::with_name("file").required(true).help("the export to apply, or - for standard input"))
+            .arg(Argument::with_name("replace").long("replace")
+                .help("removes anything not in the export, rather than merging"))
+            .arg(Argument::with_name("dry-run").long("dry-run")
+                .help("shows the changes that would be made, without making them")),
+        SubCommand::with_name("migrate").about("migrates the store to the current schema version")
+            .arg(Argument::with_name("dry-run").long("dry-run")
+                .help("shows the migrations that would be run, without running them")),
+        SubCommand::with_name("namespace").about("moves keys written without a prefix under bot.store_prefix")
+            .arg(Argument::with_name("dry-run").long("dry-run")
+                .help("shows the keys that would be moved, without moving them")),
+        SubCommand::with_name("copy").about("copies every module flag and setting from another store into this one")
+            .arg(Argument::with_name("from").required(true).help("the uri of the store to copy from, e.g. redis://localhost"))
+            .arg(Argument::with_name("prefix").long("prefix").value_name("PREFIX").takes_value(true)
+                .help("the key prefix of the store to copy from, rather than bot.store_prefix"))
+            .arg(Argument::with_name("dry-run").long("dry-run")
+                .help("shows the changes that would be made, without making them")),
+        shell::subcommand(),
+    ]
+}
+
+/// Runs the subcommand in the given matches, if there is one.  Returns the exit status, or
+/// `None` if no subcommand was given.
+pub fn run(matches: &ArgMatches, config: &Configuration) -> Option<Result<i32, Error>> {
+    match matches.subcommand() {
+        ("module", Some(matches)) => Some(Store::from(&config).and_then(|s| run_module(matches, &s))),
+        ("setting", Some(matches)) => Some(Store::from(&config).and_then(|s| run_setting(matches, &s))),
+        ("export", Some(matches)) => Some(Store::from(&config).and_then(|s| run_export(matches, &s))),
+        ("import", Some(matches)) => Some(Store::from(&config).and_then(|s| run_import(matches, &s))),
+        ("migrate", Some(matches)) => Some(Store::from(&config).and_then(|s| run_migrate(matches, &s))),
+        ("namespace", Some(matches)) => Some(Store::from(&config).and_then(|s| run_namespace(matches, &s))),
+        ("copy", Some(matches)) => Some(Store::from(&config).and_then(|s| run_copy(matches, config, &s))),
+        ("shell", Some(matches)) => Some(shell::run(matches, config)),
+        _ => None
+    }
+}
+
+fn server(matches: &ArgMatches) -> Result<ServerId, Error> {
+    Ok(ServerId(matches.value_of("server").unwrap_or("").parse()?))
+}
+
+fn run_module(matches: &ArgMatches, store: &Store) -> Result<i32, Error> {
+    let server = server(matches)?;
+    let name = matches.value_of("module").unwrap_or("");
+    let module = match module::find(name) {
+        Some(module) => module,
+        None => { eprintln!("There is no module named {}.", name); return Ok(1); }
+    };
+
+    let (scope, scoped) = match matches.value_of("channel") {
+        Some(channel) => (Scope::Channel(channel.parse()?), format!("channel {} of server {}", channel, server.0)),
+        None => (Scope::Server, format!("server {}", server.0))
+    };
+
+    match matches.value_of("action") {
+        Some("enable") => module.enable(server, scope, store)?,
+        Some("disable") => module.disable(server, scope, store)?,
+        Some("inherit") => module.inherit(server, scope, store)?,
+        _ => {}
+    }
+    let channel = match scope { Scope::Channel(channel) => Some(ChannelId(channel)), _ => None };
+    let enabled = module.is_enabled_in(server, channel, store)?;
+    println!("Module {} is {} for {}.", module.name(), if enabled { "enabled" } else { "disabled" }, scoped);
+    Ok(0)
+}
+
+fn run_setting(matches: &ArgMatches, store: &Store) -> Result<i32, Error> {
+    let server = server(matches)?;
+    let name = matches.value_of("setting").unwrap_or("");
+    let setting = match setting::find(name) {
+        Some(setting) => setting,
+        None => { eprintln!("There is no setting named {}.", name); return Ok(1); }
+    };
+    let value = matches.values_of("value").map(|v| v.collect::<Vec<_>>().join(" ")).unwrap_or_default();
+    let (scope, scoped) = match (matches.value_of("channel"), matches.value_of("user")) {
+        (Some(channel), _) => (Scope::Channel(channel.parse()?), format!("channel {} of server {}", channel, server.0)),
+        (_, Some(user)) => (Scope::User(user.parse()?), format!("user {} in server {}", user, server.0)),
+        _ => (Scope::Server, format!("server {}", server.0))
+    };
+    if !setting.allows(scope) {
+        eprintln!("Setting {} can't be set per {:?}.", setting.name(), scope.kind());
+        return Ok(1);
+    }
+
+    let success = match matches.value_of("action") {
+        Some("set") => setting.set(server, scope, &value, store)?,
+        Some("push") => match setting.push(server, scope, &value, store)? {
+            setting::Pushed::Done => true,
+            setting::Pushed::WrongKind => false,
+            setting::Pushed::Corrupt(raw) => {
+                eprintln!("Setting {} has an invalid value, {:?}; clear it first.", setting.name(), raw);
+                return Ok(1);
+            }
+        },
+        Some("clear") => { setting.clear(server, scope, store)?; true },
+        _ => true
+    };
+    if !success {
+        eprintln!("Incorrect format for setting value: `{}`.", value);
+        return Ok(1);
+    }
+
+    match setting.get(server, scope, store)? {
+        Some(value) => println!("Setting {} is {} for {}.", setting.name(), value, scoped),
+        None => println!("Setting {} is not set for {}.", setting.name(), scoped)
+    }
+    Ok(0)
+}
+
+fn run_export(matches: &ArgMatches, store: &Store) -> Result<i32, Error> {
+    let export = store.server_export(server(matches)?.0)?.to_json();
+    match matches.value_of("output") {
+        Some(path) => File::create(path)?.write_all(export.as_bytes())?,
+        None => println!("{}", export)
+    }
+    Ok(0)
+}
+
+fn run_import(matches: &ArgMatches, store: &Store) -> Result<i32, Error> {
+    let server = server(matches)?;
+    let mut contents = String::new();
+    match matches.value_of("file") {
+        Some("-") | None => io::stdin().read_to_string(&mut contents)?,
+        Some(path) => File::open(path)?.read_to_string(&mut contents)?
+    };
+    let export = Export::from_json(&contents)?;
+    let mode = if matches.is_present("replace") { ImportMode::Replace } else { ImportMode::Merge };
+
+    let rejected = export.rejected();
+    for key in &rejected { println!("{}", key); }
+    if !rejected.is_empty() && !matches.is_present("dry-run") {
+        eprintln!("{} key(s) can't be imported; nothing was changed.", rejected.len());
+        return Ok(1);
+    }
+
+    let changes = if matches.is_present("dry-run") {
+        export.diff(&store.server_export(server.0)?, mode)
+    } else {
+        store.server_import(server.0, &export, mode)?
+    };
+    for change in &changes { println!("{}", change); }
+    let verb = if matches.is_present("dry-run") { "would be made" } else { "made" };
+    println!("{} change(s) {} to server {}.", changes.len(), verb, server.0);
+    if !rejected.is_empty() {
+        println!("{} key(s) can't be imported, so the import would be refused.", rejected.len());
+        return Ok(1);
+    }
+    Ok(0)
+}
+
+fn run_migrate(matches: &ArgMatches, store: &Store) -> Result<i32, Error> {
+    let dry_run = matches.is_present("dry-run");
+    let from = store.schema_version()?;
+    let migrated = store.migrate(dry_run)?;
+    if migrated.is_empty() {
+        println!("The store is at schema version {}; there is nothing to migrate.", from);
+        return Ok(0);
+    }
+
+    for migration in &migrated {
+        println!("{} {}: {} ({} key(s))", if dry_run { "would run" } else { "ran" },
+            migration.version, migration.description, migration.changed);
+    }
+    println!("The store {} at schema version {}.", if dry_run { "would be" } else { "is now" },
+        migrated.last().map(|m| m.version).unwrap_or(from));
+    Ok(0)
+}
+
+fn run_namespace(matches: &ArgMatches, store: &Store) -> Result<i32, Error> {
+    let dry_run = matches.is_present("dry-run");
+    let moved = store.namespace(dry_run)?;
+    for &(ref from, ref to) in &moved { println!("{} -> {}", from, to); }
+    println!("{} key(s) {}.", moved.len(), if dry_run { "would be moved" } else { "moved" });
+    Ok(0)
+}
+
+/// Copies from another store, e.g. from redis into a new sqlite store.  Each server's flags
+/// and settings replace whatever this store has for it; usage statistics and scheduled jobs
+/// aren't copied.
+fn run_copy(matches: &ArgMatches, config: &Configuration, store: &Store) -> Result<i32, Error> {
+    let dry_run = matches.is_present("dry-run");
+    let mut from = (**config).clone();
+    from.store = matches.value_of("from").unwrap_or("").to_owned();
+    from.store_db = None;
+    if let Some(prefix) = matches.value_of("prefix") { from.store_prefix = prefix.to_owned(); }
+    let source = Store::from(&from)?;
+
+    let (version, expected) = (source.schema_version()?, source.expected_version());
+    if version != expected {
+        eprintln!("The store to copy from is at schema version {}, not {}; run `migrate` against it first.",
+            version, expected);
+        return Ok(1);
+    }
+    if !dry_run { store.check_schema()?; }
+
+    let servers = source.servers()?;
+    let mut changed = 0;
+    for &server in &servers {
+        let export = source.server_export(server)?;
+        for key in export.rejected() { println!("server {}: {}", server, key); }
+        let changes = if dry_run {
+            export.diff(&store.server_export(server)?, ImportMode::Replace)
+        } else {
+            store.server_import(server, &export, ImportMode::Replace)?
+        };
+        changed += changes.len();
+        println!("server {}: {} change(s)", server, changes.len());
+    }
+    println!("{} change(s) across {} server(s) {}.", changed, servers.len(),
+        if dry_run { "would be made" } else { "made" });
+    println!("Usage statistics and scheduled purges are not copied.");
+    Ok(0)
+}
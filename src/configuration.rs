@@ -1,28 +1,273 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::{Display, Formatter, Error as FmtError};
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, Read, Write, ErrorKind as IoErrorKind};
+use std::net::ToSocketAddrs;
 use std::ops::Deref;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use discord::Discord;
+use log::LogLevelFilter;
+use redis::IntoConnectionInfo;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, Visitor};
 use toml;
 use super::Error;
+use store;
 
 /// The options for configuring sharding for this server.  Sharding allows the bot to be split
 /// into multiple processes/servers easily.  Keep in mind that all DMs are processed by shard
 /// 0.  The configuration here allows the bot to split into multiple servers.  If, for example,
 /// there are 64 shards, with 8 servers in total, the 6th server should have the following values:
 /// `{ first: 40, create: 8, total: 64 }`.  First is the first shard in the server; create
-/// is the number of shards on the server; and total is the total number of shards.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// is the number of shards on the server; and total is the total number of shards.  Total
+/// may also be `"auto"`, in which case the count discord suggests is fetched at startup.
+///
+/// With `dynamic` set, `first` is ignored: each process instead claims up to `create` shards
+/// through leases in the store, and takes over the shards of processes whose leases lapse.
+///
+/// Shard numbers are read as `u16`, but no more than `MAX_SHARDS` can be connected yet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename = "shards", default)]
 pub struct Sharding {
     /// The first shard in the server.
-    pub first: u8,
+    pub first: u16,
     /// The number of shards to create.
-    pub create: u8,
-    /// The total number of shards.
-    pub total: u8
+    pub create: u16,
+    /// The total number of shards, or `"auto"` to use the count suggested by discord.
+    pub total: ShardTotal,
+    /// Whether shards are claimed through leases in the store, rather than by `first`.
+    pub dynamic: bool,
+    /// How long, in seconds, a shard lease lasts without being renewed.
+    pub lease: u64
 }
 
 impl Default for Sharding {
-    fn default() -> Sharding { Sharding { first: 0, create: 1, total: 1 } }
+    fn default() -> Sharding {
+        Sharding { first: 0, create: 1, total: ShardTotal::Fixed(1), dynamic: false, lease: 30 }
+    }
+}
+
+/// The most shards the gateway library can connect: discord-rs's `connect_sharded` takes shard
+/// numbers as a `u8`.  Going past this needs that API widened in discord-rs first.
+// TODO: drop this once discord-rs takes `u16` shard numbers.
+pub const MAX_SHARDS: u16 = 255;
+
+impl Sharding {
+    /// The total number of shards.  An automatic total is an error until it has been resolved
+    /// (see `Configuration::resolve_shards`).
+    pub fn total(&self) -> Result<u16, Error> {
+        match self.total {
+            ShardTotal::Fixed(total) => Ok(total),
+            ShardTotal::Auto => Err(Error::ConfigurationError("the automatic shard total was never resolved".into()))
+        }
+    }
+
+    /// Checks that the shards this host creates all exist.  An automatic total can't be
+    /// checked until it is resolved.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut problems = vec![];
+        if self.total == ShardTotal::Fixed(0) {
+            problems.push(Diagnostic::new("bot.shards.total", "there must be at least one shard".into(),
+                "set total to 1, to \"auto\", or to the value suggested by --suggest"));
+        }
+        if self.create == 0 {
+            problems.push(Diagnostic::new("bot.shards.create", "no shards would be created".into(),
+                "set create to the number of shards this host should run"));
+        }
+        if self.dynamic && self.lease < 3 {
+            problems.push(Diagnostic::new("bot.shards.lease", format!("a lease of {}s is too short", self.lease),
+                "leases are renewed every third of their length; use at least 3 seconds"));
+        }
+        if let ShardTotal::Fixed(total) = self.total {
+            if total > MAX_SHARDS {
+                problems.push(Diagnostic::new("bot.shards.total", format!("{} shards are more than the {} supported", total, MAX_SHARDS),
+                    &format!("use at most {} shards", MAX_SHARDS)));
+            }
+            if !self.dynamic && total != 0 && self.first as u32 + self.create as u32 > total as u32 {
+                problems.push(Diagnostic::new("bot.shards",
+                    format!("first ({}) + create ({}) is more than total ({})", self.first, self.create, total),
+                    "shards first through first + create - 1 must all be less than total"));
+            }
+        }
+        problems
+    }
+
+    /// Splits `total` shards as evenly as possible over `hosts` hosts, returning the layout
+    /// for each host.  Earlier hosts take any remainder.
+    pub fn layout(total: u16, hosts: u16) -> Vec<Sharding> {
+        let hosts = if hosts > total { total } else { hosts };
+        let (base, remainder) = (total / hosts, total % hosts);
+        let mut first = 0;
+        (0..hosts).map(|host| {
+            let create = base + if host < remainder { 1 } else { 0 };
+            let sharding = Sharding { first, create, total: ShardTotal::Fixed(total), ..Sharding::default() };
+            first += create;
+            sharding
+        }).collect()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShardTotal { Auto, Fixed(u16) }
+
+impl ::std::str::FromStr for ShardTotal {
+    type Err = ::std::num::ParseIntError;
+    fn from_str(value: &str) -> Result<ShardTotal, Self::Err> {
+        if value == "auto" { Ok(ShardTotal::Auto) } else { value.parse().map(ShardTotal::Fixed) }
+    }
+}
+
+impl Display for ShardTotal {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            &ShardTotal::Auto => write!(f, "auto"),
+            &ShardTotal::Fixed(total) => write!(f, "{}", total)
+        }
+    }
+}
+
+impl Serialize for ShardTotal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            &ShardTotal::Auto => serializer.serialize_str("auto"),
+            &ShardTotal::Fixed(total) => serializer.serialize_u16(total)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ShardTotal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ShardTotal, D::Error> {
+        struct TotalVisitor;
+        impl<'de> Visitor<'de> for TotalVisitor {
+            type Value = ShardTotal;
+            fn expecting(&self, f: &mut Formatter) -> Result<(), FmtError> {
+                write!(f, "a number of shards, or \"auto\"")
+            }
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<ShardTotal, E> {
+                if value < 0 || value > u16::max_value() as i64 {
+                    Err(E::custom(format!("{} is not a valid number of shards", value)))
+                } else { Ok(ShardTotal::Fixed(value as u16)) }
+            }
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<ShardTotal, E> {
+                self.visit_i64(value as i64)
+            }
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<ShardTotal, E> {
+                if value == "auto" { Ok(ShardTotal::Auto) }
+                else { Err(E::custom(format!("`{}` is not a number of shards, or \"auto\"", value))) }
+            }
+        }
+        deserializer.deserialize_any(TotalVisitor)
+    }
+}
+
+/// Options for the command usage statistics kept in the store.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename = "stats", default)]
+pub struct Stats {
+    /// The number of days of command usage to keep.  Older usage expires from the store.
+    pub retention: u32,
+}
+
+impl Default for Stats {
+    fn default() -> Stats { Stats { retention: 30 } }
+}
+
+/// Options for the data kept for each server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename = "data", default)]
+pub struct Data {
+    /// The number of days a server's modules, settings, and aliases are kept after the bot
+    /// leaves it.  If the bot is added back within that time, nothing is lost.
+    pub grace: u32,
+}
+
+impl Default for Data {
+    fn default() -> Data { Data { grace: 7 } }
+}
+
+/// Options for the prometheus metrics listener.  The same listener serves the `/healthz` and
+/// `/readyz` health checks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename = "metrics", default)]
+pub struct Metrics {
+    /// Whether or not to serve metrics at all.
+    pub enabled: bool,
+    /// The address to listen on; metrics are served at `/metrics`.
+    pub bind: String,
+    /// The number of seconds a shard's event loop may go without ticking before the shard is
+    /// no longer considered live.
+    pub liveness_timeout: u64,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics { enabled: false, bind: String::from("127.0.0.1:9102"), liveness_timeout: 300 }
+    }
+}
+
+/// Options for the local admin API, which allows modules and settings to be changed without
+/// using commands on the server itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename = "admin", default)]
+pub struct Admin {
+    /// Whether or not to serve the admin API at all.
+    pub enabled: bool,
+    /// The address to listen on.  This should not be reachable from outside of the host.
+    pub bind: String,
+    /// The token that every request must carry.  The API is not served without one.  This is
+    /// never written to the configuration file.
+    #[serde(skip_serializing)]
+    pub token: String,
+    /// The id of the user who holds the token, recorded in each server's history as having
+    /// made the changes made through the API.  0 records them as made by the API itself.
+    pub user: u64,
+}
+
+impl Default for Admin {
+    fn default() -> Admin {
+        Admin { enabled: false, bind: String::from("127.0.0.1:9103"), token: String::new(), user: 0 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat { Text, Json }
+
+/// Options for logging.  Records are written to the terminal, a file, or both.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename = "logging", default)]
+pub struct Logging {
+    /// The level to log at, unless `-v` is given: one of off, error, warn, info, debug, trace.
+    pub level: String,
+    /// Either `text`, or `json` for one JSON object per line.
+    pub format: LogFormat,
+    /// Whether to log to the terminal (on stderr).
+    pub terminal: bool,
+    /// A file to log to, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// The size in bytes at which the log file is rotated; 0 never rotates it.
+    pub max_size: u64,
+    /// The number of rotated log files to keep.
+    pub keep: u32,
+    /// Levels for specific modules, overriding `level`; e.g. `"wonderful::store" = "debug"`.
+    pub modules: BTreeMap<String, String>,
+}
+
+impl Default for Logging {
+    fn default() -> Logging {
+        Logging {
+            level: String::from("warn"),
+            format: LogFormat::Text,
+            terminal: true,
+            file: None,
+            max_size: 10 * 1024 * 1024,
+            keep: 5,
+            modules: BTreeMap::new()
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -35,10 +280,28 @@ pub struct Bot {
     pub owners: Vec<i64>,
     /// The default command prefix.
     pub prefix: String,
-    /// The uri to the redis server.
+    /// The uri to the redis server, or `sqlite://path` to store everything in a sqlite
+    /// database instead.
     pub store: String,
-    /// The bot token.
+    /// A file to read the uri to the redis server from, instead of `store`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store_file: Option<String>,
+    /// The redis database to use, instead of the one in `store`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store_db: Option<i64>,
+    /// Prepended to every key, e.g. `"wonderful:"`, for sharing a redis with other services.
+    /// Use the `namespace` subcommand to move existing keys under a new prefix.
+    pub store_prefix: String,
+    /// How long, in seconds, module flags and settings are cached for.  Changes are normally
+    /// seen at once, since every write tells every process to drop the old value; this bounds
+    /// how long a change can go unseen if that message is lost.  0 turns the cache off.
+    pub cache_ttl: u64,
+    /// The bot token.  This is never written to the configuration file.
+    #[serde(skip_serializing)]
     pub token: String,
+    /// A file to read the bot token from, instead of `token`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<String>,
     /// Handling sharding.
     pub shards: Sharding,
 }
@@ -51,24 +314,114 @@ impl Default for Bot {
             shards: Sharding::default(),
             prefix: String::from("!"),
             token: String::new(),
-            store: String::from("redis://wonder@localhost/0")
+            token_file: None,
+            store: String::from("redis://wonder@localhost/0"),
+            store_file: None,
+            store_db: None,
+            store_prefix: String::new(),
+            cache_ttl: 30
         }
     }
 }
 
+/// Every key that can be overridden, from the environment or the command line.  The matching
+/// environment variable is the key in upper case, with dots replaced by underscores, prefixed
+/// by `WONDERFUL_`; e.g. `shards.total` is `WONDERFUL_SHARDS_TOTAL`.
+pub static OVERRIDE_KEYS: &'static [&'static str] = &[
+    "name", "owners", "prefix", "store", "store_file", "store_db", "store_prefix", "cache_ttl", "token", "token_file",
+    "shards.first", "shards.create", "shards.total", "shards.dynamic", "shards.lease"
+];
+
+impl Bot {
+    /// Overrides a single key.  Setting `token` or `store` directly discards any `_file`
+    /// indirection from a lower layer, and vice versa.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        match key {
+            "name" => self.name = value.to_owned(),
+            "owners" => self.owners = value.split(',').map(|o| o.trim()).filter(|o| !o.is_empty())
+                .map(|o| o.parse()).collect::<Result<Vec<_>, _>>()?,
+            "prefix" => self.prefix = value.to_owned(),
+            "store" => { self.store = value.to_owned(); self.store_file = None; },
+            "store_file" => self.store_file = Some(value.to_owned()),
+            "store_db" => self.store_db = Some(value.parse()?),
+            "store_prefix" => self.store_prefix = value.to_owned(),
+            "cache_ttl" => self.cache_ttl = value.parse()?,
+            "token" => { self.token = value.to_owned(); self.token_file = None; },
+            "token_file" => self.token_file = Some(value.to_owned()),
+            "shards.first" => self.shards.first = value.parse()?,
+            "shards.create" => self.shards.create = value.parse()?,
+            "shards.total" => self.shards.total = value.parse()?,
+            "shards.dynamic" => self.shards.dynamic = value.parse()?,
+            "shards.lease" => self.shards.lease = value.parse()?,
+            _ => return Err(Error::ConfigurationError(format!("unknown configuration key `{}`", key)))
+        }
+        Ok(())
+    }
+
+    fn apply_environment(&mut self) -> Result<(), Error> {
+        for key in OVERRIDE_KEYS {
+            let variable = format!("WONDERFUL_{}", key.to_uppercase().replace('.', "_"));
+            if let Ok(value) = env::var(&variable) {
+                trace!("Overriding {} from {}...", key, variable);
+                self.set(key, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_files(&mut self) -> Result<(), Error> {
+        if let Some(ref path) = self.token_file { self.token = read_secret(path)?; }
+        if let Some(ref path) = self.store_file { self.store = read_secret(path)?; }
+        Ok(())
+    }
+}
+
+fn read_secret(path: &str) -> Result<String, Error> {
+    trace!("Reading secret from {}...", path);
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Ok(contents.trim().to_owned())
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(default)]
-struct Config { bot: Bot }
+struct Config {
+    bot: Bot,
+    stats: Stats,
+    data: Data,
+    metrics: Metrics,
+    admin: Admin,
+    logging: Logging,
+    /// The configuration for each plug, keyed by the plug's configuration name.  These are
+    /// checked and parsed by the plugs themselves; see `PlugConfig`.
+    plugs: BTreeMap<String, toml::Value>
+}
 
 impl Default for Config {
-    fn default() -> Config { Config { bot: Bot::default() } }
+    fn default() -> Config {
+        Config {
+            bot: Bot::default(),
+            stats: Stats::default(),
+            data: Data::default(),
+            metrics: Metrics::default(),
+            admin: Admin::default(),
+            logging: Logging::default(),
+            plugs: BTreeMap::new()
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Configuration(String, Bot);
+pub struct Configuration(String, Config);
 
 impl Configuration {
-    pub fn from(name: &str) -> Result<Configuration, Error> {
+    pub fn from(name: &str) -> Result<Configuration, Error> { Configuration::load(name, &[]) }
+
+    /// Loads the configuration file, and applies overrides to the `[bot]` table.  In order of
+    /// precedence, values come from: the given overrides (`key=value`, usually from the
+    /// command line); `WONDERFUL_*` environment variables; the file; and then the defaults.
+    /// `token_file` and `store_file` are read once every override has been applied.
+    pub fn load(name: &str, overrides: &[&str]) -> Result<Configuration, Error> {
         let name = String::from(name);
         trace!("Opening file {} for configuration...", name);
         let file = File::open(&name)?;
@@ -77,22 +430,230 @@ impl Configuration {
         trace!("Reading configuration file...");
         reader.read_to_string(&mut contents)?;
         trace!("Loading configuration...");
-        let config = toml::from_str::<Config>(&contents)?;
-        Ok(Configuration(name, config.bot))
+        let mut config = toml::from_str::<Config>(&contents)?;
+        trace!("Applying overrides...");
+        config.bot.apply_environment()?;
+        for item in overrides {
+            let mut parts = item.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => config.bot.set(key.trim(), value)?,
+                _ => return Err(Error::ConfigurationError(format!("override `{}` is not key=value", item)))
+            }
+        }
+        config.bot.resolve_files()?;
+        Ok(Configuration(name, config))
+    }
+
+    /// Replaces an automatic shard total with the count discord suggests for the bot, and
+    /// checks this host's layout against it.  Does nothing if the total is fixed.
+    pub fn resolve_shards(&mut self) -> Result<(), Error> {
+        if self.1.bot.shards.total != ShardTotal::Auto { return Ok(()); }
+        let discord = Discord::from_bot_token(&self.1.bot.token)?;
+        let suggested = discord.suggested_shard_count()?;
+        if suggested > MAX_SHARDS as u64 {
+            return Err(Error::ConfigurationError(format!("discord suggested {} shards, but at most {} are supported",
+                suggested, MAX_SHARDS)));
+        }
+        info!("Using the suggested shard total of {}.", suggested);
+        self.1.bot.shards.total = ShardTotal::Fixed(suggested as u16);
+        let problems = self.1.bot.shards.validate();
+        if !problems.is_empty() {
+            let problems = problems.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+            return Err(Error::ConfigurationError(problems.join("; ")));
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> &Stats { &self.1.stats }
+    pub fn data(&self) -> &Data { &self.1.data }
+    pub fn metrics(&self) -> &Metrics { &self.1.metrics }
+    pub fn admin(&self) -> &Admin { &self.1.admin }
+    pub fn logging(&self) -> &Logging { &self.1.logging }
+    /// The `[plugs.<name>]` table for the given plug, if there is one.
+    pub fn plug(&self, name: &str) -> Option<&toml::Value> { self.1.plugs.get(name) }
+}
+
+/// A single problem found while validating a configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The path of the offending key in the configuration file, e.g. `bot.shards.total`.
+    pub key: String,
+    pub problem: String,
+    pub hint: String,
+}
+
+impl Diagnostic {
+    pub fn new(key: &str, problem: String, hint: &str) -> Diagnostic {
+        Diagnostic { key: key.to_owned(), problem, hint: hint.to_owned() }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "{}: {} (hint: {})", self.key, self.problem, self.hint)
+    }
+}
+
+impl Configuration {
+    /// Checks the configuration for every problem that would otherwise only show up once the
+    /// bot has started.  This doesn't connect to anything.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut problems = vec![];
+        let bot = &self.1.bot;
+        let shards = &bot.shards;
+
+        if bot.token.trim().is_empty() {
+            problems.push(Diagnostic::new("bot.token", "no token is set".into(),
+                "set `token` or `token_file`, or the WONDERFUL_TOKEN environment variable"));
+        }
+        if bot.prefix.is_empty() {
+            problems.push(Diagnostic::new("bot.prefix", "the prefix is empty".into(),
+                "set a prefix such as \"!\""));
+        }
+        match store::sqlite_path(&bot.store) {
+            Some(path) => {
+                if path.is_empty() {
+                    problems.push(Diagnostic::new("bot.store", "the sqlite store has no path".into(),
+                        "use the form sqlite://path/to/file.db"));
+                }
+                if shards.dynamic {
+                    problems.push(Diagnostic::new("bot.shards.dynamic", "dynamic shards need a redis store".into(),
+                        "use a fixed range of shards, or a redis store"));
+                }
+            },
+            None => if let Err(e) = bot.store.as_str().into_connection_info() {
+                problems.push(Diagnostic::new("bot.store", format!("`{}` is not a valid redis uri: {}", bot.store, e),
+                    "use the form redis://[:password@]host[:port][/db], or sqlite://path"));
+            }
+        }
+        if bot.store_db.map(|db| db < 0).unwrap_or(false) {
+            problems.push(Diagnostic::new("bot.store_db", "the database can't be negative".into(),
+                "remove store_db to use the database in the store uri"));
+        }
+        if bot.store_prefix.contains(|c: char| "*?[]\\".contains(c)) || bot.store_prefix.contains(char::is_whitespace) {
+            problems.push(Diagnostic::new("bot.store_prefix", format!("`{}` contains a wildcard or space", bot.store_prefix),
+                "use letters, digits, and separators such as `:`, e.g. \"wonderful:\""));
+        }
+        problems.append(&mut shards.validate());
+        if self.1.stats.retention == 0 {
+            problems.push(Diagnostic::new("stats.retention", "usage would never be kept".into(),
+                "set retention to a number of days, such as 30"));
+        }
+        if self.1.metrics.enabled {
+            if let Err(e) = self.1.metrics.bind.to_socket_addrs() {
+                problems.push(Diagnostic::new("metrics.bind", format!("`{}` is not an address: {}", self.1.metrics.bind, e),
+                    "use the form host:port, such as 127.0.0.1:9102"));
+            }
+            if self.1.metrics.liveness_timeout == 0 {
+                problems.push(Diagnostic::new("metrics.liveness_timeout", "shards would never be live".into(),
+                    "set liveness_timeout to a number of seconds, such as 300"));
+            }
+        }
+        if self.1.admin.enabled {
+            if let Err(e) = self.1.admin.bind.to_socket_addrs() {
+                problems.push(Diagnostic::new("admin.bind", format!("`{}` is not an address: {}", self.1.admin.bind, e),
+                    "use the form host:port, such as 127.0.0.1:9103"));
+            }
+            if self.1.admin.token.trim().is_empty() {
+                problems.push(Diagnostic::new("admin.token", "the admin API is enabled without a token".into(),
+                    "set a long, random token, or disable the admin API"));
+            }
+        }
+        if self.1.logging.level.parse::<LogLevelFilter>().is_err() {
+            problems.push(Diagnostic::new("logging.level", format!("`{}` is not a log level", self.1.logging.level),
+                "use one of off, error, warn, info, debug, or trace"));
+        }
+        for (module, level) in &self.1.logging.modules {
+            if level.parse::<LogLevelFilter>().is_err() {
+                problems.push(Diagnostic::new(&format!("logging.modules.{}", module),
+                    format!("`{}` is not a log level", level), "use one of off, error, warn, info, debug, or trace"));
+            }
+        }
+
+        problems
+    }
+}
+
+/// A configuration that can be reloaded while the bot is running.  Only the bot's name, owners,
+/// and prefix, and the stats and data options, are reloaded; everything else is fixed when the shards
+/// start, and changes to it are reported rather than applied.
+#[derive(Debug)]
+pub struct Reloadable {
+    current: RwLock<Configuration>,
+    generation: AtomicUsize,
+    overrides: Vec<String>,
+}
+
+impl Reloadable {
+    pub fn new(configuration: Configuration, overrides: &[&str]) -> Reloadable {
+        Reloadable {
+            current: RwLock::new(configuration),
+            generation: AtomicUsize::new(0),
+            overrides: overrides.iter().map(|o| o.to_string()).collect()
+        }
+    }
+
+    pub fn get(&self) -> Configuration {
+        match self.current.read() { Ok(c) => c.clone(), Err(poisoned) => poisoned.into_inner().clone() }
     }
+
+    /// Incremented every time a reload is applied, so that holders of a copy know to refresh it.
+    pub fn generation(&self) -> usize { self.generation.load(Ordering::SeqCst) }
+
+    /// Re-reads and validates the configuration file, then swaps in the reloadable fields.
+    /// Returns the keys that changed but need a restart to apply.
+    pub fn reload(&self) -> Result<Vec<&'static str>, Error> {
+        let old = self.get();
+        let overrides = self.overrides.iter().map(|o| &o[..]).collect::<Vec<_>>();
+        let mut fresh = Configuration::load(&old.0, &overrides)?;
+        // An automatic total is only resolved at startup; keep the resolved one.
+        if fresh.1.bot.shards.total == ShardTotal::Auto { fresh.1.bot.shards.total = old.1.bot.shards.total; }
+        let problems = fresh.validate();
+        if !problems.is_empty() {
+            let problems = problems.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+            return Err(Error::ConfigurationError(problems.join("; ")));
+        }
+
+        let (old_bot, new_bot) = (&old.1.bot, &fresh.1.bot);
+        let mut restart = vec![];
+        if old_bot.token != new_bot.token { restart.push("bot.token"); }
+        if old_bot.store != new_bot.store || old_bot.store_db != new_bot.store_db ||
+            old_bot.store_prefix != new_bot.store_prefix { restart.push("bot.store"); }
+        if old_bot.cache_ttl != new_bot.cache_ttl { restart.push("bot.cache_ttl"); }
+        if old_bot.shards != new_bot.shards { restart.push("bot.shards"); }
+        if old.1.metrics != fresh.1.metrics { restart.push("metrics"); }
+        if old.1.admin != fresh.1.admin { restart.push("admin"); }
+        if old.1.logging != fresh.1.logging { restart.push("logging"); }
+        if old.1.plugs != fresh.1.plugs { restart.push("plugs"); }
+
+        let mut merged = old.clone();
+        merged.1.bot.name = new_bot.name.clone();
+        merged.1.bot.owners = new_bot.owners.clone();
+        merged.1.bot.prefix = new_bot.prefix.clone();
+        merged.1.stats = fresh.1.stats.clone();
+        merged.1.data = fresh.1.data.clone();
+
+        match self.current.write() { Ok(mut c) => *c = merged, Err(poisoned) => *poisoned.into_inner() = merged }
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        Ok(restart)
+    }
+
+    pub fn file(&self) -> String { self.get().0 }
 }
 
 impl Deref for Configuration {
     type Target = Bot;
-    fn deref(&self) -> &Bot { &self.1 }
+    fn deref(&self) -> &Bot { &self.1.bot }
 }
 
-pub fn create_unless_exists(name: &str) -> Result<(), Error> {
+/// Writes a default configuration file, unless one already exists.  `plugs` holds the default
+/// `[plugs.<name>]` tables.
+pub fn create_unless_exists(name: &str, plugs: BTreeMap<String, toml::Value>) -> Result<(), Error> {
     let open = OpenOptions::new().create_new(true).write(true).open(name);
     match open {
         Ok(mut f) => {
             let dumped =
-                toml::to_string::<Config>(&Config::default())
+                toml::to_string::<Config>(&Config { plugs, ..Config::default() })
                 .map_err(|e| -> Error { e.into() })?;
             f.write_all(dumped.as_bytes())?;
             Ok(())
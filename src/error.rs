@@ -7,8 +7,12 @@ use toml::de::Error as TomlDeError;
 use toml::ser::Error as TomlSerError;
 use discord::Error as DiscordError;
 use redis::RedisError;
+use rusqlite::Error as SqliteError;
+use hyper::Error as HyperError;
+use serde_json::Error as JsonError;
 use std::convert::From;
 use hyper;
+use store;
 
 #[derive(Debug)]
 pub enum Error {
@@ -19,6 +23,10 @@ pub enum Error {
     TomlSerError(TomlSerError),
     DiscordError(DiscordError),
     RedisError(RedisError),
+    SqliteError(SqliteError),
+    HyperError(HyperError),
+    JsonError(JsonError),
+    ConfigurationError(String),
 }
 
 impl Error {
@@ -29,9 +37,28 @@ impl Error {
                 s == hyper::status::StatusCode::NotFound,
             &Error::ParseIntError(_) => true,
             &Error::ParseBoolError(_) => true,
+            &Error::RedisError(ref e) => store::is_transient(e),
+            &Error::SqliteError(ref e) => store::is_busy(e),
             _ => false
         }
     }
+
+    /// The name of the variant, for use in metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Error::ParseIntError(_) => "ParseIntError",
+            &Error::ParseBoolError(_) => "ParseBoolError",
+            &Error::IoError(_) => "IoError",
+            &Error::TomlDeError(_) => "TomlDeError",
+            &Error::TomlSerError(_) => "TomlSerError",
+            &Error::DiscordError(_) => "DiscordError",
+            &Error::RedisError(_) => "RedisError",
+            &Error::SqliteError(_) => "SqliteError",
+            &Error::HyperError(_) => "HyperError",
+            &Error::JsonError(_) => "JsonError",
+            &Error::ConfigurationError(_) => "ConfigurationError",
+        }
+    }
 }
 
 impl Display for Error {
@@ -50,6 +77,10 @@ impl TraitError for Error {
             &Error::TomlSerError(ref e) => e.description(),
             &Error::DiscordError(ref e) => e.description(),
             &Error::RedisError(ref e) => e.description(),
+            &Error::SqliteError(ref e) => e.description(),
+            &Error::HyperError(ref e) => e.description(),
+            &Error::JsonError(ref e) => e.description(),
+            &Error::ConfigurationError(ref e) => &e[..],
         }
     }
     fn cause(&self) -> Option<&TraitError> {
@@ -61,6 +92,10 @@ impl TraitError for Error {
             &Error::TomlSerError(ref e) => Some(e),
             &Error::DiscordError(ref e) => Some(e),
             &Error::RedisError(ref e) => Some(e),
+            &Error::SqliteError(ref e) => Some(e),
+            &Error::HyperError(ref e) => Some(e),
+            &Error::JsonError(ref e) => Some(e),
+            &Error::ConfigurationError(_) => None,
         }
     }
 }
@@ -82,4 +117,7 @@ error_from!(
     TomlDeError => TomlDeError,
     TomlSerError => TomlSerError,
     DiscordError => DiscordError,
-    RedisError => RedisError);
+    RedisError => RedisError,
+    SqliteError => SqliteError,
+    HyperError => HyperError,
+    JsonError => JsonError);
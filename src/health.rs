@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::Instant;
+use serde_json;
+use store::Store;
+
+#[derive(Debug, Clone)]
+struct Status { connected: bool, ready: bool, failed: bool, tick: Instant }
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ShardReport {
+    pub index: u16,
+    pub connected: bool,
+    pub ready: bool,
+    pub failed: bool,
+    /// Seconds since the shard's event loop last ticked.
+    pub idle: u64,
+    pub live: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub ok: bool,
+    pub redis: Option<bool>,
+    pub shards: Vec<ShardReport>,
+}
+
+lazy_static! {
+    static ref SHARDS: RwLock<BTreeMap<u16, Status>> = RwLock::new(BTreeMap::new());
+}
+
+fn update<F: FnOnce(&mut Status)>(index: u16, f: F) {
+    let mut shards = match SHARDS.write() { Ok(shards) => shards, Err(poisoned) => poisoned.into_inner() };
+    let status = shards.entry(index).or_insert_with(||
+        Status { connected: false, ready: false, failed: false, tick: Instant::now() });
+    f(status);
+}
+
+/// Registers a shard that this process is expected to run, before it has connected.
+pub fn expect(index: u16) { update(index, |_| ()) }
+/// Marks a shard as having built its context, i.e. connected to the gateway.
+pub fn connected(index: u16) { update(index, |s| { s.connected = true; s.tick = Instant::now(); }) }
+/// Marks a shard as having received its ready event.
+pub fn ready(index: u16) { update(index, |s| { s.ready = true; s.tick = Instant::now(); }) }
+/// Marks a shard's event loop as having run.
+pub fn tick(index: u16) { update(index, |s| s.tick = Instant::now()) }
+/// Marks a shard as having stopped because of an error.
+pub fn failed(index: u16) { update(index, |s| { s.failed = true; s.ready = false; }) }
+/// Stops reporting on a shard that this process is no longer expected to run.
+pub fn forget(index: u16) {
+    let mut shards = match SHARDS.write() { Ok(shards) => shards, Err(poisoned) => poisoned.into_inner() };
+    shards.remove(&index);
+}
+
+fn shards(timeout: u64) -> Vec<ShardReport> {
+    let shards = match SHARDS.read() { Ok(shards) => shards, Err(poisoned) => poisoned.into_inner() };
+    shards.iter().map(|(index, status)| {
+        let idle = status.tick.elapsed().as_secs();
+        ShardReport {
+            index: *index, connected: status.connected, ready: status.ready, failed: status.failed,
+            idle, live: !status.failed && idle <= timeout
+        }
+    }).collect()
+}
+
+/// Live means that every shard's event loop has ticked within the last `timeout` seconds.
+pub fn liveness(timeout: u64) -> Report {
+    let shards = shards(timeout);
+    Report { ok: shards.iter().all(|s| s.live), redis: None, shards }
+}
+
+/// Ready means that every shard has connected and received its ready event, and that the
+/// store answers.  The store is reported as `redis` whichever backend it is.
+pub fn readiness(timeout: u64, store: &Store) -> Report {
+    let shards = shards(timeout);
+    let redis = match store.ping() {
+        Ok(_) => true,
+        Err(e) => { warn!("Readiness check could not reach the store: {:?}", e); false }
+    };
+    Report { ok: redis && shards.iter().all(|s| s.connected && s.ready && !s.failed), redis: Some(redis), shards }
+}
+
+impl Report {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| String::from("{}"))
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use rand::{self, Rng};
+use configuration::Reloadable;
+use error::Error;
+use shard::{Outbound, PlugConfigs, PlugSet, Shard};
+use store::Store;
+
+/// A shard lease held by this process.  The store expires the lease `ttl` after it was last
+/// renewed; the local deadline is taken from before the renewal was sent, less a margin, so
+/// that it always passes before the store lets another process claim the shard.
+#[derive(Debug)]
+pub struct Lease {
+    deadline: Mutex<Instant>,
+    revoked: AtomicBool,
+}
+
+impl Lease {
+    fn new(started: Instant, ttl: Duration) -> Lease {
+        Lease { deadline: Mutex::new(started + usable(ttl)), revoked: AtomicBool::new(false) }
+    }
+
+    /// Whether the shard may still run.  Once this is false, it stays false.
+    pub fn held(&self) -> bool {
+        if self.revoked.load(Ordering::SeqCst) { return false; }
+        let deadline = match self.deadline.lock() { Ok(d) => *d, Err(poisoned) => *poisoned.into_inner() };
+        if Instant::now() < deadline { true } else { self.revoke(); false }
+    }
+
+    /// Gives up the lease; the shard stops within a second, and the lease is released once it
+    /// has.
+    pub fn revoke(&self) { self.revoked.store(true, Ordering::SeqCst) }
+
+    fn extend(&self, started: Instant, ttl: Duration) {
+        match self.deadline.lock() {
+            Ok(mut d) => *d = started + usable(ttl),
+            Err(poisoned) => *poisoned.into_inner() = started + usable(ttl)
+        }
+    }
+}
+
+/// A tenth of the lease is kept back, in case the clocks of this process and the store drift.
+fn usable(ttl: Duration) -> Duration { ttl - ttl / 10 }
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Runs shards claimed through leases in the store, rather than a fixed range.  Every third of
+/// the lease length, held leases are renewed and, if this process holds fewer than `create`
+/// shards, free ones are claimed.  A shard whose lease can't be renewed stops before the lease
+/// lapses in the store, so no shard is ever run by two processes at once; a shard that stops
+/// for any other reason revokes its lease.  A lost or revoked lease is released, and its shard
+/// may be claimed here again, only once the shard's threads have exited.  Free shards that no
+/// process has room for are warned about.  Only returns if the store can't be opened.
+pub fn run(reloadable: Arc<Reloadable>, plugs: PlugSet, configs: PlugConfigs, outbound: Outbound) -> Result<(), Error> {
+    let config = reloadable.get();
+    let (create, total) = (config.shards.create as usize, config.shards.total()?);
+    let ttl = Duration::from_secs(config.shards.lease);
+    let store = Store::from(&config)?;
+    let owner = format!("{}:{}", process::id(), rand::thread_rng().gen::<u32>());
+    let mut held: BTreeMap<u16, Arc<Lease>> = BTreeMap::new();
+    let mut stopping: BTreeMap<u16, Arc<Lease>> = BTreeMap::new();
+    let (mut orphaned, mut warned) = (vec![], vec![]);
+    info!("Claiming up to {} of {} shards as {}...", create, total, owner);
+
+    loop {
+        for (shard, lease) in &held {
+            if !lease.held() { continue; }
+            let started = Instant::now();
+            match store.lease_renew(*shard, &owner, millis(ttl)) {
+                Ok(true) => lease.extend(started, ttl),
+                Ok(false) => { warn!("Lost the lease on shard {}.", shard); lease.revoke(); },
+                Err(e) => warn!("Could not renew the lease on shard {}; will retry: {:?}", shard, e)
+            }
+        }
+
+        let lost = held.iter().filter(|&(_, lease)| !lease.held()).map(|(shard, _)| *shard).collect::<Vec<_>>();
+        for shard in lost {
+            if let Some(lease) = held.remove(&shard) { stopping.insert(shard, lease); }
+        }
+
+        // The shard thread and its gateway thread each keep a reference to the lease until
+        // they exit, so only the one here is left once the connection is closed.
+        let stopped = stopping.iter().filter(|&(_, lease)| Arc::strong_count(lease) == 1)
+            .map(|(shard, _)| *shard).collect::<Vec<_>>();
+        for shard in stopped {
+            stopping.remove(&shard);
+            if let Err(e) = store.lease_release(shard, &owner) {
+                warn!("Could not release the lease on shard {}: {:?}", shard, e);
+            }
+        }
+
+        match store.lease_free(total) {
+            Ok(mut free) => {
+                // A shard still stopping here may have outlived its lease in the store.
+                free.retain(|shard| !stopping.contains_key(shard));
+                // Start from a random shard, so that processes starting together spread out.
+                rand::thread_rng().shuffle(&mut free);
+                let room = create.saturating_sub(held.len() + stopping.len());
+                for shard in free.iter().cloned().take(room) {
+                    let started = Instant::now();
+                    match store.lease_acquire(shard, &owner, millis(ttl)) {
+                        Ok(true) => {
+                            info!("Claimed the lease on shard {}.", shard);
+                            let lease = Arc::new(Lease::new(started, ttl));
+                            held.insert(shard, lease.clone());
+                            let shard = Shard::new(shard, reloadable.clone(), plugs.clone(), configs.clone())
+                                .with_lease(lease).with_outbound(outbound);
+                            thread::spawn(move || shard.call());
+                        },
+                        Ok(false) => {},
+                        Err(e) => warn!("Could not claim shard {}: {:?}", shard, e)
+                    }
+                }
+
+                // Shards left free for two rounds in a row have likely lost their process, e.g.
+                // to a dead host, and nobody has room for them.  They are warned about once,
+                // until they change.
+                let mut unclaimed = free.into_iter().skip(room).collect::<Vec<_>>();
+                unclaimed.sort();
+                let lingering = unclaimed.iter().filter(|shard| orphaned.contains(*shard)).cloned().collect::<Vec<_>>();
+                if !lingering.is_empty() && lingering != warned {
+                    warn!("Shard(s) {:?} are free, but this process already runs {} shard(s); unless another \
+                        process has room, raise bot.shards.create or add a host.", lingering, held.len());
+                }
+                warned = lingering;
+                orphaned = unclaimed;
+            },
+            Err(e) => warn!("Could not look for free shards: {:?}", e)
+        }
+
+        thread::sleep(ttl / 3);
+    }
+}
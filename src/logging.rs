@@ -0,0 +1,171 @@
+use std::cell::Cell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord};
+use configuration::{Logging, LogFormat};
+use error::Error;
+
+thread_local! {
+    static SHARD: Cell<Option<u16>> = Cell::new(None);
+    static SERVER: Cell<Option<u64>> = Cell::new(None);
+}
+
+/// Marks every record logged from the current thread as coming from the given shard.
+pub fn set_shard(shard: u16) { SHARD.with(|s| s.set(Some(shard))) }
+/// Marks every record logged from the current thread as being about the given server, until
+/// it is set again.
+pub fn set_server(server: Option<u64>) { SERVER.with(|s| s.set(server)) }
+
+struct FileSink { path: String, file: File, size: u64, max_size: u64, keep: u32 }
+
+impl FileSink {
+    fn open(path: &str, max_size: u64, keep: u32) -> Result<FileSink, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(FileSink { path: path.to_owned(), file, size, max_size, keep })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.max_size > 0 && self.size + line.len() as u64 > self.max_size { self.rotate()?; }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Moves `path` to `path.1`, `path.1` to `path.2`, and so on, dropping the oldest file.
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.keep).rev() {
+            let from = format!("{}.{}", self.path, index);
+            if fs::metadata(&from).is_ok() { fs::rename(&from, format!("{}.{}", self.path, index + 1))?; }
+        }
+        if self.keep > 0 { fs::rename(&self.path, format!("{}.1", self.path))?; }
+        else { fs::remove_file(&self.path)?; }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+struct Logger {
+    level: LogLevelFilter,
+    modules: Vec<(String, LogLevelFilter)>,
+    format: LogFormat,
+    terminal: bool,
+    file: Option<Mutex<FileSink>>,
+}
+
+impl Logger {
+    /// The level for a target: the longest matching module override, or the default.
+    fn level_for(&self, target: &str) -> LogLevelFilter {
+        self.modules.iter()
+            .filter(|&&(ref module, _)| target == module.as_str() || target.starts_with(&format!("{}::", module)))
+            .max_by_key(|&&(ref module, _)| module.len())
+            .map(|&(_, level)| level).unwrap_or(self.level)
+    }
+
+    fn format(&self, record: &LogRecord) -> String {
+        let shard = SHARD.with(|s| s.get());
+        let server = SERVER.with(|s| s.get());
+        match self.format {
+            LogFormat::Json => {
+                let mut line = json!({
+                    "time": timestamp(),
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string()
+                });
+                if let Some(shard) = shard { line["shard"] = json!(shard); }
+                if let Some(server) = server { line["server"] = json!(server.to_string()); }
+                format!("{}\n", line)
+            },
+            LogFormat::Text => {
+                let mut fields = String::new();
+                if let Some(shard) = shard { fields.push_str(&format!(" shard={}", shard)); }
+                if let Some(server) = server { fields.push_str(&format!(" server={}", server)); }
+                format!("{} [{}] {}: {}{}\n", timestamp(), record.level(), record.target(), record.args(), fields)
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) { return; }
+        let line = self.format(record);
+        if self.terminal { let _ = io::stderr().write_all(line.as_bytes()); }
+        if let Some(ref file) = self.file {
+            let mut file = match file.lock() { Ok(f) => f, Err(poisoned) => poisoned.into_inner() };
+            if let Err(e) = file.write(&line) {
+                let _ = writeln!(io::stderr(), "Could not write to the log file: {}", e);
+            }
+        }
+    }
+}
+
+fn parse_level(key: &str, value: &str) -> Result<LogLevelFilter, Error> {
+    value.parse().map_err(|_| Error::ConfigurationError(format!("{} is not a log level: `{}`", key, value)))
+}
+
+/// The level given by the number of `-v` flags, if any were given.
+pub fn verbosity(occurrences: u64) -> Option<LogLevelFilter> {
+    match occurrences {
+        0 => None,
+        1 => Some(LogLevelFilter::Info),
+        2 => Some(LogLevelFilter::Debug),
+        _ => Some(LogLevelFilter::Trace),
+    }
+}
+
+/// Installs the logger described by the `[logging]` table.  `-v` flags, if given, take the
+/// place of the configured default level; module overrides still apply.
+pub fn init(verbosity: Option<LogLevelFilter>, config: &Logging) -> Result<(), Error> {
+    let level = match verbosity { Some(level) => level, None => parse_level("logging.level", &config.level)? };
+    let mut modules = vec![];
+    for (module, value) in &config.modules {
+        modules.push((module.clone(), parse_level(&format!("logging.modules.{}", module), value)?));
+    }
+    let file = match config.file {
+        Some(ref path) => Some(Mutex::new(FileSink::open(path, config.max_size, config.keep)?)),
+        None => None
+    };
+
+    let maximum = modules.iter().map(|&(_, level)| level).fold(level, |a, b| if b > a { b } else { a });
+    let logger = Logger { level, modules, format: config.format, terminal: config.terminal, file };
+    log::set_logger(|max| { max.set(maximum); Box::new(logger) })
+        .map_err(|e| Error::ConfigurationError(format!("could not install the logger: {}", e)))?;
+    warn!("Logging enabled; level {}", level);
+    Ok(())
+}
+
+/// Installs a terminal logger, for errors that happen before the configuration is loaded.
+pub fn init_default(verbosity: Option<LogLevelFilter>) {
+    let logger = Logger {
+        level: verbosity.unwrap_or(LogLevelFilter::Warn), modules: vec![],
+        format: LogFormat::Text, terminal: true, file: None
+    };
+    let _ = log::set_logger(|max| { max.set(logger.level); Box::new(logger) });
+}
+
+/// The current time in UTC, as `YYYY-MM-DDTHH:MM:SS.mmmZ`.
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (days, seconds) = ((now.as_secs() / 86400) as i64, now.as_secs() % 86400);
+    // Converts days since the epoch to a civil date; see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day,
+        seconds / 3600, seconds / 60 % 60, seconds % 60, now.subsec_nanos() / 1_000_000)
+}
@@ -5,22 +5,38 @@ extern crate log;
 #[macro_use]
 extern crate lazy_static;
 extern crate shellwords;
-extern crate simplelog;
 extern crate serde;
+#[macro_use]
+extern crate serde_json;
 extern crate toml;
 extern crate clap;
 extern crate redis;
+extern crate rusqlite;
 extern crate discord;
 extern crate hyper;
 extern crate rand;
 extern crate regex;
+#[macro_use]
+extern crate chan;
+extern crate chan_signal;
 
+mod admin;
+mod cli;
 mod configuration;
 mod error;
+mod health;
+mod lease;
+mod logging;
+mod metrics;
+mod reload;
+mod scheduler;
 mod shard;
+mod shell;
 pub mod store;
 
 use std::error::Error as TraitError;
+use std::sync::Arc;
+use chan_signal::Signal;
 use self::error::Error;
 use self::configuration::Configuration;
 use self::store::Store;
@@ -36,24 +52,34 @@ fn init_app<'a>() -> Application<'a, 'a> {
         .value_name("FILE")
         .help("sets a custom config file")
         .takes_value(true));
+    let app = app.arg(Argument::with_name("override")
+        .short("o").long("override")
+        .value_name("KEY=VALUE")
+        .help("overrides a [bot] configuration value, e.g. shards.total=4")
+        .takes_value(true).multiple(true).number_of_values(1));
+    let app = app.arg(Argument::with_name("check-config")
+        .long("check-config").help("validates the configuration, and exits"));
+    let app = app.arg(Argument::with_name("dry-run")
+        .long("dry-run")
+        .help("logs everything the bot would send, as warnings with the dry_run target, instead of sending it"));
     let app = app.arg(Argument::with_name("v")
         .short("v").multiple(true).help("sets level of verbosity"));
     let app = app.arg(Argument::with_name("s")
         .short("s").long("suggest").help("suggests sharding configuration"));
-    app
+    let app = app.arg(Argument::with_name("hosts")
+        .long("hosts").value_name("N").requires("s")
+        .help("with --suggest, splits the suggested shards over N hosts")
+        .takes_value(true));
+    app.subcommands(cli::subcommands())
 }
 
 #[inline]
-fn init_logging(occurrences: u64) {
-    let level = match occurrences {
-        0 => simplelog::LogLevelFilter::Warn,
-        1 => simplelog::LogLevelFilter::Info,
-        2 => simplelog::LogLevelFilter::Debug,
-        _ => simplelog::LogLevelFilter::Trace,
-    };
-
-    simplelog::TermLogger::init(level, simplelog::Config::default()).unwrap();
-    warn!("Logging enabled; level {}", level);
+fn init_logging(occurrences: u64, config: &Configuration) {
+    let verbosity = logging::verbosity(occurrences);
+    logging::init(verbosity, config.logging()).unwrap_or_else(|e| {
+        logging::init_default(verbosity);
+        handle_error(e)
+    });
 }
 
 fn handle_error(e: Error) -> ! {
@@ -67,37 +93,136 @@ fn handle_error(e: Error) -> ! {
 #[inline]
 fn init_store(config: &Configuration) {
     trace!("Creating initial datastore connection...");
-    Store::from(&config.store).unwrap_or_else(|e| handle_error(e));
+    let store = Store::from(&config).unwrap_or_else(|e| handle_error(e));
+    store.check_schema().unwrap_or_else(|e| handle_error(e));
+    store::listen_for_invalidations(&config).unwrap_or_else(|e| handle_error(e));
 }
 
 #[inline]
-fn init_config(name: &str) -> Configuration {
-    configuration::create_unless_exists(name).unwrap_or_else(|e| handle_error(e));
-    Configuration::from(name).unwrap_or_else(|e| handle_error(e))
+fn init_config(name: &str, overrides: &[&str], occurrences: u64) -> Configuration {
+    // The logger is configured by the configuration file, so until it is loaded, errors go
+    // to a default logger.
+    configuration::create_unless_exists(name, shard::plugs::default_configs())
+        .and_then(|_| Configuration::load(name, overrides))
+        .unwrap_or_else(|e| {
+            logging::init_default(logging::verbosity(occurrences));
+            handle_error(e)
+        })
+}
+
+fn validate_config(config: &Configuration) -> (Vec<configuration::Diagnostic>, Option<shard::PlugConfigs>) {
+    let mut problems = config.validate();
+    let configs = match shard::plugs::load_configs(config) {
+        Ok(configs) => Some(configs),
+        Err(mut plug_problems) => { problems.append(&mut plug_problems); None }
+    };
+    (problems, configs)
+}
+
+fn check_config(config: &Configuration) -> Option<shard::PlugConfigs> {
+    let (problems, configs) = validate_config(config);
+    for problem in &problems { error!("{}", problem); }
+    if problems.is_empty() { configs } else { None }
+}
+
+fn handle_check_config(name: &str, overrides: &[&str]) -> ! {
+    let config = match Configuration::load(name, overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}: could not be loaded: {}", name, e);
+            std::process::exit(1);
+        }
+    };
+
+    let (problems, _) = validate_config(&config);
+    for problem in &problems { eprintln!("{}: {}", name, problem); }
+    if problems.is_empty() {
+        println!("{}: configuration is valid.", name);
+        std::process::exit(0);
+    } else {
+        std::process::exit(1);
+    }
 }
 
-fn handle_suggest(config: &Configuration) {
+fn handle_suggest(config: &Configuration, hosts: Option<&str>) {
     trace!("loading discord...");
     let discord = discord::Discord::from_bot_token(&config.token)
         .unwrap_or_else(|e| handle_error(e.into()));
     trace!("retrieving suggested sharding...");
     let suggest = discord.suggested_shard_count()
         .unwrap_or_else(|e| handle_error(e.into()));
-    println!("total = {}", suggest);
+    if suggest > configuration::MAX_SHARDS as u64 {
+        handle_error(Error::ConfigurationError(format!("discord suggests {} shards, but at most {} are supported",
+            suggest, configuration::MAX_SHARDS)));
+    }
+    let hosts = match hosts {
+        Some(hosts) => hosts.parse::<u16>().unwrap_or_else(|e| handle_error(e.into())),
+        None => { println!("total = {}", suggest); return; }
+    };
+    if hosts == 0 || suggest == 0 {
+        handle_error(Error::ConfigurationError(format!("can't split {} shards over {} hosts", suggest, hosts)));
+    }
+
+    let layout = configuration::Sharding::layout(suggest as u16, hosts);
+    for (host, shards) in layout.iter().enumerate() {
+        if host > 0 { println!(); }
+        println!("# host {} of {}", host + 1, layout.len());
+        println!("[bot.shards]");
+        println!("first = {}", shards.first);
+        println!("create = {}", shards.create);
+        println!("total = {}", shards.total);
+    }
 }
 
 fn main() {
+    // This must happen before any other thread is spawned, so that they all block SIGHUP.
+    let signals = chan_signal::notify(&[Signal::HUP]);
     let matches = init_app().get_matches();
-    init_logging(matches.occurrences_of("v"));
-    let config = init_config(matches.value_of("config").unwrap_or("config.toml"));
+    let overrides = matches.values_of("override").map(|v| v.collect::<Vec<_>>()).unwrap_or_default();
+    let name = matches.value_of("config").unwrap_or("config.toml");
+    if matches.is_present("check-config") { handle_check_config(name, &overrides); }
+    let mut config = init_config(name, &overrides, matches.occurrences_of("v"));
+    init_logging(matches.occurrences_of("v"), &config);
+    // Subcommands don't connect to discord, so they don't need a valid configuration.
+    if let Some(result) = cli::run(&matches, &config) {
+        std::process::exit(result.unwrap_or_else(|e| handle_error(e)));
+    }
+    let configs = check_config(&config).unwrap_or_else(|| {
+        error!("The configuration is invalid; see above.  Use --check-config to check it again.");
+        std::process::exit(1);
+    });
 
-    if matches.is_present("s") { handle_suggest(&config); return; }
+    if matches.is_present("s") { handle_suggest(&config, matches.value_of("hosts")); return; }
+    config.resolve_shards().unwrap_or_else(|e| handle_error(e));
 
     let commands = shard::init();
+    if !config.shards.dynamic {
+        (0..config.shards.create).for_each(|i| health::expect(config.shards.first + i));
+    }
     init_store(&config);
+    let _metrics = metrics::serve(&config).unwrap_or_else(|e| handle_error(e));
+    let _admin = admin::serve(&config).unwrap_or_else(|e| handle_error(e));
+    scheduler::spawn(&config).unwrap_or_else(|e| handle_error(e));
+    let reloadable = Arc::new(configuration::Reloadable::new(config.clone(), &overrides));
+    reload::spawn(signals, reloadable.clone());
+
+    let outbound = if matches.is_present("dry-run") {
+        if config.shards.dynamic {
+            // A dry run would hold leases, keeping the live processes from running those shards.
+            handle_error(Error::ConfigurationError("--dry-run can't be used with dynamic shards".into()));
+        }
+        warn!("Running in dry-run mode; nothing will be sent to discord.");
+        shard::Outbound::DryRun
+    } else { shard::Outbound::Discord };
+
+    if config.shards.dynamic {
+        lease::run(reloadable, commands, configs, outbound).unwrap_or_else(|e| handle_error(e));
+        return;
+    }
 
     (0..config.shards.create).into_iter()
-        .map(|i| shard::Shard::new(i, config.clone(), commands.clone()))
+        .map(|i| shard::Shard::new(config.shards.first + i, reloadable.clone(), commands.clone(), configs.clone())
+            .with_outbound(outbound))
         .map(|s| std::thread::spawn(move || s.call()))
         .collect::<Vec<_>>().into_iter()
         .map(|t| t.join())
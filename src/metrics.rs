@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use discord::model::Event;
+use hyper::header::ContentType;
+use hyper::server::{Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use configuration::Configuration;
+use error::Error;
+use health;
+use store::Store;
+
+/// The upper bounds, in seconds, of the buckets used by every latency histogram.
+static BUCKETS: &'static [f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Clone, Default)]
+struct Histogram { buckets: Vec<u64>, sum: f64, count: u64 }
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        if self.buckets.is_empty() { self.buckets = vec![0; BUCKETS.len()]; }
+        if let Some(index) = BUCKETS.iter().position(|bound| seconds <= *bound) { self.buckets[index] += 1; }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += *count;
+            let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels.trim_right_matches(','), self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels.trim_right_matches(','), self.count);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    events: BTreeMap<(u16, &'static str), u64>,
+    plugs: BTreeMap<String, Histogram>,
+    commands: BTreeMap<(String, bool), u64>,
+    errors: BTreeMap<&'static str, u64>,
+    redis: BTreeMap<&'static str, Histogram>,
+    cache: BTreeMap<&'static str, u64>,
+    servers: BTreeMap<u16, usize>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+fn with_registry<F: FnOnce(&mut Registry)>(f: F) {
+    match REGISTRY.lock() {
+        Ok(mut registry) => f(&mut registry),
+        Err(poisoned) => f(&mut poisoned.into_inner())
+    }
+}
+
+pub fn event(shard: u16, event: &Event) {
+    let kind = event_name(event);
+    with_registry(|r| *r.events.entry((shard, kind)).or_insert(0) += 1);
+}
+
+pub fn plug(plug: String, elapsed: Duration) {
+    with_registry(|r| r.plugs.entry(plug).or_insert_with(Histogram::default).observe(elapsed));
+}
+
+pub fn command(name: &str, success: bool) {
+    with_registry(|r| *r.commands.entry((name.to_owned(), success)).or_insert(0) += 1);
+}
+
+pub fn error(error: &Error) {
+    with_registry(|r| *r.errors.entry(error.name()).or_insert(0) += 1);
+}
+
+pub fn servers(shard: u16, count: usize) {
+    with_registry(|r| { r.servers.insert(shard, count); });
+}
+
+/// Runs the given redis call, recording how long it took under the given operation name.
+pub fn redis<T, F: FnOnce() -> T>(operation: &'static str, f: F) -> T {
+    let started = Instant::now();
+    let result = f();
+    let elapsed = started.elapsed();
+    with_registry(|r| r.redis.entry(operation).or_insert_with(Histogram::default).observe(elapsed));
+    result
+}
+
+/// Records reads through the settings cache that were, and weren't, cached.
+pub fn cache(hits: usize, misses: usize) {
+    with_registry(|r| {
+        *r.cache.entry("hit").or_insert(0) += hits as u64;
+        *r.cache.entry("miss").or_insert(0) += misses as u64;
+    });
+}
+
+/// Renders every metric in the prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+    with_registry(|r| {
+        header(&mut out, "wonderful_events_total", "Gateway events received.", "counter");
+        for (&(shard, kind), count) in &r.events {
+            let _ = writeln!(out, "wonderful_events_total{{shard=\"{}\",type=\"{}\"}} {}", shard, kind, count);
+        }
+
+        header(&mut out, "wonderful_plug_duration_seconds", "Time taken by plugs to handle an event.", "histogram");
+        for (plug, histogram) in &r.plugs {
+            histogram.render(&mut out, "wonderful_plug_duration_seconds", &format!("plug=\"{}\",", escape(plug)));
+        }
+
+        header(&mut out, "wonderful_commands_total", "Commands dispatched.", "counter");
+        for (&(ref name, success), count) in &r.commands {
+            let _ = writeln!(out, "wonderful_commands_total{{command=\"{}\",outcome=\"{}\"}} {}",
+                escape(name), if success { "success" } else { "failure" }, count);
+        }
+
+        header(&mut out, "wonderful_errors_total", "Errors returned by plugs.", "counter");
+        for (kind, count) in &r.errors {
+            let _ = writeln!(out, "wonderful_errors_total{{kind=\"{}\"}} {}", kind, count);
+        }
+
+        header(&mut out, "wonderful_redis_duration_seconds", "Time taken by redis calls.", "histogram");
+        for (operation, histogram) in &r.redis {
+            histogram.render(&mut out, "wonderful_redis_duration_seconds", &format!("operation=\"{}\",", operation));
+        }
+
+        header(&mut out, "wonderful_cache_reads_total", "Module flags and settings read through the cache.", "counter");
+        for (result, count) in &r.cache {
+            let _ = writeln!(out, "wonderful_cache_reads_total{{result=\"{}\"}} {}", result, count);
+        }
+
+        header(&mut out, "wonderful_servers", "Servers known to each shard.", "gauge");
+        for (shard, count) in &r.servers {
+            let _ = writeln!(out, "wonderful_servers{{shard=\"{}\"}} {}", shard, count);
+        }
+    });
+    out
+}
+
+/// Starts the metrics listener, if it is enabled.  Alongside metrics, the listener serves the
+/// `/healthz` and `/readyz` health checks.  The listener runs until the returned value is
+/// dropped.
+pub fn serve(config: &Configuration) -> Result<Option<Listening>, Error> {
+    if !config.metrics().enabled { return Ok(None); }
+    info!("Serving metrics on {}...", config.metrics().bind);
+    let timeout = config.metrics().liveness_timeout;
+    let store = Store::from(&config)?;
+    let server = Server::http(&config.metrics().bind[..])?;
+    let listening = server.handle(move |request: Request, mut response: Response| {
+        match request.uri {
+            RequestUri::AbsolutePath(ref path) if path == "/metrics" => {
+                response.headers_mut().set(ContentType("text/plain; version=0.0.4".parse().unwrap()));
+                let _ = response.send(render().as_bytes());
+            },
+            RequestUri::AbsolutePath(ref path) if path == "/healthz" || path == "/readyz" => {
+                let report = if path == "/healthz" { health::liveness(timeout) }
+                    else { health::readiness(timeout, &store) };
+                if !report.ok { *response.status_mut() = StatusCode::ServiceUnavailable; }
+                response.headers_mut().set(ContentType::json());
+                let _ = response.send(report.to_json().as_bytes());
+            },
+            _ => {
+                *response.status_mut() = StatusCode::NotFound;
+                let _ = response.send(b"not found\n");
+            }
+        }
+    })?;
+    Ok(Some(listening))
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn event_name(event: &Event) -> &'static str {
+    match event {
+        &Event::Ready { .. } => "Ready",
+        &Event::Resumed { .. } => "Resumed",
+        &Event::MessageCreate { .. } => "MessageCreate",
+        &Event::MessageUpdate { .. } => "MessageUpdate",
+        &Event::MessageDelete { .. } => "MessageDelete",
+        &Event::MessageDeleteBulk { .. } => "MessageDeleteBulk",
+        &Event::TypingStart { .. } => "TypingStart",
+        &Event::PresenceUpdate { .. } => "PresenceUpdate",
+        &Event::ServerCreate { .. } => "ServerCreate",
+        &Event::ServerUpdate { .. } => "ServerUpdate",
+        &Event::ServerDelete { .. } => "ServerDelete",
+        &Event::ServerMemberAdd { .. } => "ServerMemberAdd",
+        &Event::ServerMemberUpdate { .. } => "ServerMemberUpdate",
+        &Event::ServerMemberRemove { .. } => "ServerMemberRemove",
+        &Event::ServerMembersChunk { .. } => "ServerMembersChunk",
+        &Event::ChannelCreate { .. } => "ChannelCreate",
+        &Event::ChannelUpdate { .. } => "ChannelUpdate",
+        &Event::ChannelDelete { .. } => "ChannelDelete",
+        &Event::VoiceStateUpdate { .. } => "VoiceStateUpdate",
+        &Event::ReactionAdd { .. } => "ReactionAdd",
+        &Event::ReactionRemove { .. } => "ReactionRemove",
+        &Event::Unknown { .. } => "Unknown",
+        _ => "Other"
+    }
+}
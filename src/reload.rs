@@ -0,0 +1,56 @@
+use std::fs;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use chan;
+use chan_signal::Signal;
+use configuration::Reloadable;
+
+/// How often the configuration file is checked for changes.
+static POLL_INTERVAL: u64 = 5;
+
+fn modified(file: &str) -> Option<SystemTime> {
+    fs::metadata(file).and_then(|m| m.modified()).ok()
+}
+
+fn reload(configuration: &Reloadable) {
+    info!("Reloading configuration from {}...", configuration.file());
+    match configuration.reload() {
+        Ok(ref restart) if restart.is_empty() => info!("Configuration reloaded."),
+        Ok(restart) => {
+            info!("Configuration reloaded.");
+            warn!("These changes need a restart, and have not been applied: {}", restart.join(", "));
+        },
+        Err(e) => error!("Could not reload the configuration; keeping the current one: {}", e)
+    }
+}
+
+/// Reloads the configuration whenever a SIGHUP is received, or the configuration file
+/// changes.  The signal receiver must be created before any other thread is spawned.
+pub fn spawn(signals: chan::Receiver<Signal>, configuration: Arc<Reloadable>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let file = configuration.file();
+        let tick = chan::tick(Duration::from_secs(POLL_INTERVAL));
+        let mut last = modified(&file);
+
+        loop {
+            chan_select! {
+                signals.recv() -> signal => match signal {
+                    Some(Signal::HUP) => {
+                        reload(&configuration);
+                        last = modified(&file);
+                    },
+                    Some(_) => {},
+                    None => return
+                },
+                tick.recv() => {
+                    let current = modified(&file);
+                    if current != last {
+                        last = current;
+                        reload(&configuration);
+                    }
+                },
+            }
+        }
+    })
+}
@@ -0,0 +1,90 @@
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use discord::model::ServerId;
+use configuration::Configuration;
+use error::Error;
+use store::Store;
+
+/// How often, in seconds, the store is checked for due jobs.
+static INTERVAL: u64 = 60;
+/// How long, in seconds, a claimed job has to complete before another process runs it again.
+static RETRY: u64 = 600;
+/// The most jobs run in a single check.
+static BATCH: usize = 100;
+
+/// Work to be done later, by whichever process gets to it first.  Jobs are kept in the store,
+/// so they survive restarts; a job may run more than once, so running one must be idempotent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Job {
+    /// Deletes everything stored for a server the bot has left.
+    Purge(ServerId),
+}
+
+impl Job {
+    fn name(&self) -> String {
+        match self { &Job::Purge(server) => format!("purge:{}", server.0) }
+    }
+
+    fn parse(name: &str) -> Option<Job> {
+        let mut parts = name.splitn(2, ':');
+        match (parts.next(), parts.next().and_then(|id| id.parse().ok())) {
+            (Some("purge"), Some(server)) => Some(Job::Purge(ServerId(server))),
+            _ => None
+        }
+    }
+
+    fn run(&self, store: &Store) -> Result<(), Error> {
+        match self {
+            &Job::Purge(server) => {
+                let deleted = store.server_purge(server.0)?;
+                info!("Purged {} key(s) for server {}, which the bot left.", deleted, server);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Schedules a job to run after `delay` seconds, replacing any earlier schedule for it.
+pub fn schedule(job: Job, delay: u64, store: &Store) -> Result<(), Error> {
+    store.schedule_add(&job.name(), now() + delay)
+}
+
+/// Unschedules a job.  Returns whether it was scheduled.
+pub fn cancel(job: Job, store: &Store) -> Result<bool, Error> {
+    store.schedule_remove(&job.name())
+}
+
+/// Runs due jobs in the background, checking every `INTERVAL` seconds.  Every process runs
+/// this; each due job is claimed by exactly one of them.
+pub fn spawn(config: &Configuration) -> Result<JoinHandle<()>, Error> {
+    let store = Store::from(config)?;
+    Ok(thread::Builder::new().name(String::from("scheduler")).spawn(move || loop {
+        if let Err(e) = run_due(&store) { warn!("Could not run scheduled jobs; will retry: {:?}", e); }
+        thread::sleep(Duration::from_secs(INTERVAL));
+    })?)
+}
+
+fn run_due(store: &Store) -> Result<(), Error> {
+    let now = now();
+    for name in store.schedule_due(now, BATCH)? {
+        let retry = now + RETRY;
+        if !store.schedule_claim(&name, now, retry)? { continue; }
+        match Job::parse(&name) {
+            Some(job) => {
+                debug!("Running scheduled job {}...", name);
+                if let Err(e) = job.run(store) {
+                    // The job stays claimed, so it is tried again once the claim lapses.
+                    warn!("Scheduled job {} failed; it will be retried: {:?}", name, e);
+                    continue;
+                }
+            },
+            None => warn!("Dropping unknown scheduled job {}.", name)
+        }
+        store.schedule_complete(&name, retry)?;
+    }
+    Ok(())
+}
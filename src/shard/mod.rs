@@ -1,62 +1,216 @@
 #[macro_use]
 mod plug;
-mod plugs;
+pub(crate) mod plugs;
+pub(crate) mod synthetic;
 mod util;
 
 pub use self::plug::*;
 pub use self::plugs::init;
 
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::Duration;
 use super::{Configuration, Error};
+use super::configuration::{Reloadable, MAX_SHARDS};
 use super::store::Store;
+use metrics;
 use discord::{Discord, Connection, State};
+use discord::Error as DiscordError;
+use discord::model::Event;
+use health;
+use lease::Lease;
+use logging;
+
+/// How often, in seconds, a shard with a lease checks it while no events arrive.
+const LEASE_CHECK: u64 = 1;
 
 pub struct Shard {
-    pub index: u8,
+    pub index: u16,
+    /// The configuration as it was when the shard was created.  Plugs should use
+    /// `Context::configuration`, which follows reloads.
     pub configuration: Configuration,
-    plugs: PlugSet
+    reloadable: Arc<Reloadable>,
+    plugs: PlugSet,
+    configs: PlugConfigs,
+    /// The lease the shard runs under, if shards are claimed dynamically.
+    lease: Option<Arc<Lease>>,
+    outbound: Outbound
+}
+
+/// Where the messages that plugs send go.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outbound {
+    Discord,
+    /// Printed to the terminal, by `shell`.
+    Terminal,
+    /// Logged as warnings, with the `dry_run` target, rather than sent; see `--dry-run`.
+    DryRun,
 }
 
 pub struct Context<'a> {
     pub shard: &'a Shard,
-    pub discord: Discord,
-    pub connection: Connection,
+    /// Only used through `util`, which respects `outbound`.
+    discord: Discord,
+    pub outbound: Outbound,
     pub store: Store,
     pub state: State,
+    /// The current configuration; refreshed before each event when it has been reloaded.
+    pub configuration: Configuration,
+    generation: usize,
+    /// The name of the command being handled, if any; set by `Plug::handle_event` so that
+    /// the `PlugSet` can record its usage.
+    pub dispatched: Option<String>,
 }
 
 impl Shard {
-    pub fn new(index: u8, configuration: Configuration, plugs: PlugSet) -> Shard {
-        Shard { index, configuration, plugs }
+    pub fn new(index: u16, reloadable: Arc<Reloadable>, plugs: PlugSet, configs: PlugConfigs) -> Shard {
+        Shard { index, configuration: reloadable.get(), reloadable, plugs, configs, lease: None, outbound: Outbound::Discord }
     }
 
-    fn store(&self) -> Result<Store, Error> { Store::from(&self.configuration.store) }
+    /// Runs the shard only while the given lease is held.
+    pub fn with_lease(mut self, lease: Arc<Lease>) -> Shard { self.lease = Some(lease); self }
+
+    /// Sends everything plugs send to the given place, rather than to discord.
+    pub fn with_outbound(mut self, outbound: Outbound) -> Shard { self.outbound = outbound; self }
+
+    fn holds_lease(&self) -> bool { self.lease.as_ref().map(|l| l.held()).unwrap_or(true) }
+
+    fn store(&self) -> Result<Store, Error> { Store::from(&self.configuration) }
     fn discord(&self) -> Result<Discord, Error> {
         Discord::from_bot_token(&self.configuration.token).map_err(|e| e.into())
     }
-    fn context(&self) -> Result<Context, Error> {
+    fn context(&self) -> Result<(Context, Connection), Error> {
         let discord = self.discord()?;
         let store = self.store()?;
-        let (connection, ready) = discord.connect_sharded(self.index, self.configuration.shards.total)?;
+        let total = self.configuration.shards.total()?;
+        // `Sharding::validate` reports this before any shard starts; see `MAX_SHARDS`.  Past
+        // the check, both numbers fit the `u8`s the gateway library takes.
+        if total > MAX_SHARDS || self.index >= total {
+            return Err(Error::ConfigurationError(format!("shard {} of {} can't be connected; at most {} shards are supported",
+                self.index, total, MAX_SHARDS)));
+        }
+        let (connection, ready) = discord.connect_sharded(self.index as u8, total as u8)?;
         let state = State::new(ready);
-        Ok(Context { shard: &self, discord, connection, store, state })
+        let generation = self.reloadable.generation();
+        let configuration = self.reloadable.get();
+        let outbound = self.outbound;
+        Ok((Context { shard: &self, discord, outbound, store, state, configuration, generation, dispatched: None },
+            connection))
     }
 
+    /// Builds a context around the given state, without connecting to the gateway.
+    pub fn offline_context(&self, state: State, outbound: Outbound) -> Result<Context, Error> {
+        let discord = self.discord()?;
+        let store = self.store()?;
+        let generation = self.reloadable.generation();
+        let configuration = self.reloadable.get();
+        Ok(Context { shard: &self, discord, outbound, store, state, configuration, generation, dispatched: None })
+    }
+
+    /// Runs the shard until it fails or, with a lease, until the lease is lost.  A shard with
+    /// a lease gives it up however it stops, so that it can be claimed and run again.
     pub fn call(self) {
+        logging::set_shard(self.index);
+        let _release = self.lease.clone().map(|lease| Release(self.index, lease));
+        if let Err(e) = self.run() {
+            health::failed(self.index);
+            if self.lease.is_none() { ::handle_error(e) }
+            error!("Shard {} stopped, and gives up its lease: {:?}", self.index, e);
+        }
+    }
+
+    fn run(&self) -> Result<(), Error> {
         trace!("Building context...");
-        let context = self.context().unwrap_or_else(|e| ::handle_error(e));
+        let (context, connection) = self.context()?;
+        health::connected(self.index);
+        health::ready(self.index);
         trace!("Beginning event loop...");
-        watch(context).unwrap_or_else(|e| ::handle_error(e));
+        let (sender, events) = mpsc::sync_channel(0);
+        let lease = self.lease.clone();
+        thread::Builder::new().name(format!("shard-{}-gateway", self.index))
+            .spawn(move || read(connection, sender, lease))?;
+        watch(context, events)
+    }
+}
+
+/// Revokes a shard's lease when dropped, even by a panic, so that `lease::run` stops renewing
+/// it.
+struct Release(u16, Arc<Lease>);
+
+impl Drop for Release {
+    fn drop(&mut self) {
+        self.1.revoke();
+        health::forget(self.0);
     }
 }
 
-fn watch(mut context: Context) -> Result<(), Error> {
-    context.shard.plugs.trigger_start(&mut context)?;
+impl<'a> Context<'a> {
+    /// The parsed `[plugs.<name>]` configuration of a plug.
+    pub fn plug_config<T: PlugConfig>(&self) -> &T { self.shard.configs.get::<T>() }
+
+    /// Lets every plug know the shard is starting.
+    pub fn start(&mut self) -> Result<(), Error> {
+        let shard = self.shard;
+        shard.plugs.trigger_start(self)
+    }
+
+    /// Applies an event to the state, and passes it to every plug.
+    pub fn dispatch(&mut self, event: &Event) -> Result<(), Error> {
+        self.state.update(event);
+        self.refresh_configuration();
+        let shard = self.shard;
+        shard.plugs.trigger_event(event, self)
+    }
 
+    fn refresh_configuration(&mut self) {
+        let generation = self.shard.reloadable.generation();
+        if generation != self.generation {
+            debug!("Configuration was reloaded; refreshing...");
+            self.configuration = self.shard.reloadable.get();
+            self.generation = generation;
+        }
+    }
+}
+
+/// Passes gateway events to the shard until it stops taking them, then shuts the connection
+/// down.  Holds a reference to the shard's lease, so that `lease::run` neither releases nor
+/// claims the shard again before the connection is closed.  The gateway library can only be
+/// shut down between events, so a connection whose shard has stopped lingers, unused, until
+/// its next event.
+fn read(mut connection: Connection, events: SyncSender<Result<Event, DiscordError>>, _lease: Option<Arc<Lease>>) {
     loop {
         debug!("Polling for an event...");
-        let event = context.connection.recv_event()?;
-        context.state.update(&event);
-        context.shard.plugs.trigger_event(&event, &mut context)?;
+        let event = connection.recv_event();
+        let failed = event.is_err();
+        if events.send(event).is_err() || failed { break; }
+    }
+    if let Err(e) = connection.shutdown() {
+        debug!("Could not shut the connection down cleanly: {:?}", e);
+    }
+}
+
+fn watch(mut context: Context, events: Receiver<Result<Event, DiscordError>>) -> Result<(), Error> {
+    context.start()?;
+
+    loop {
+        // The lease is checked while waiting as well, so that a quiet shard stops in time.
+        let event = match events.recv_timeout(Duration::from_secs(LEASE_CHECK)) {
+            Ok(event) => Some(event?),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) =>
+                return Err(Error::ConfigurationError("the gateway connection closed unexpectedly".into())),
+        };
+        if !context.shard.holds_lease() {
+            warn!("No longer holding the lease on this shard; stopping.");
+            return Ok(());
+        }
+        let event = match event { Some(event) => event, None => continue };
+        health::tick(context.shard.index);
+        if let Event::Ready(_) = event { health::ready(context.shard.index); }
+        metrics::event(context.shard.index, &event);
+        context.dispatch(&event)?;
+        metrics::servers(context.shard.index, context.state.servers().len());
     }
 
     // context.shard.plugs.trigger_stop(&mut context)
@@ -1,17 +1,31 @@
 #![allow(unused_variables)]
 
+use std::any::Any;
+use std::collections::BTreeMap;
 use std::error::Error as TraitError;
 use std::sync::Arc;
 use std::ops::{Deref, DerefMut};
 use std::fmt::Debug;
-use discord::model::{Event, Message};
+use std::time::Instant;
+use discord::model::{Event, Message, ServerId};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use toml;
+use configuration::{Configuration, Diagnostic};
 use super::{Context, Error};
+use super::util;
+use super::plugs::configuration::module;
+use logging;
+use metrics;
 use shellwords;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PlugStatus {
     Continue,
     Stop,
+    /// The command was handled, but failed for a reason that was shown to its author, e.g. a
+    /// bad argument; stops like `Stop`, but is counted as an error in the usage statistics.
+    Failed,
 }
 
 pub type PlugResult = Result<PlugStatus, Error>;
@@ -33,28 +47,48 @@ pub trait Plug: Debug {
     fn handle_start(&self, context: &mut Context) -> PlugResult { Ok(PlugStatus::Continue) }
     fn handle_stop(&self, context: &mut Context) -> PlugResult { Ok(PlugStatus::Continue) }
     fn matches_name(&self, &str) -> bool { false }
-    fn command_prefix<'a>(&'a self, message: &Message, context: &'a mut Context) -> &'a str { &context.shard.configuration.prefix }
+    /// Whether this plug accepts every command name; such plugs are not counted as providing
+    /// a command (e.g. when checking aliases for collisions).
+    fn is_fallback(&self) -> bool { false }
     fn handle_command(&self, command: &Command, context: &mut Context) -> PlugResult { Ok(PlugStatus::Continue) }
-    fn handle_event(&self, event: &Event, context: &mut Context) -> PlugResult {
-        match event {
-            &Event::MessageCreate(ref message) => self.handle_message(&message, context),
+    /// Handles an event.  `command` is the command in the event's message, if it has one,
+    /// with its alias already resolved; by default, it is passed to `handle_command` if this
+    /// plug matches its name.
+    fn handle_event(&self, event: &Event, command: Option<&Command>, context: &mut Context) -> PlugResult {
+        match command {
+            Some(command) if self.matches_name(command.name) => {
+                context.dispatched = Some(command.name.to_owned());
+                self.handle_command(command, context)
+            },
             _ => Ok(PlugStatus::Continue)
         }
     }
-    fn handle_message(&self, message: &Message, context: &mut Context) -> PlugResult {
-        let prefix = {
-            let prefix = self.command_prefix(message, context);
-            if !message.content.starts_with(prefix) { return Ok(PlugStatus::Continue); }
-            prefix.len()
-        };
-        let name = message.content.chars().skip(prefix)
-            .take_while(|c| !char::is_whitespace(*c)).collect::<String>();
-        if !self.matches_name(&name) { return Ok(PlugStatus::Continue); }
-        let arguments = build_arguments(&message.content[(name.len() + 1)..]);
-        let arguments = arguments.iter().map(|s| &s[..]).collect::<Vec<_>>();
-        let command = Command { name: &name, arguments: &arguments[..], message };
-        self.handle_command(&command, context)
-    }
+}
+
+/// A prefixed message's command, with its alias resolved, and its arguments.
+struct Parsed { name: String, arguments: Vec<String> }
+
+/// Parses the command in a message, if it starts with the prefix.  Aliases are only resolved
+/// once per message, here, rather than by each plug.  Where the `commands` module is disabled,
+/// only `configure` is recognised, so that it can be turned back on.
+fn parse_command(message: &Message, context: &Context) -> Result<Option<Parsed>, Error> {
+    let prefix = &context.configuration.prefix;
+    if !message.content.starts_with(&prefix[..]) { return Ok(None); }
+    let rest = &message.content[prefix.len()..];
+    let typed = rest.split(char::is_whitespace).next().unwrap_or("");
+    let name = match util::server_for(message.channel_id, context) {
+        Some(server) => {
+            let name = util::resolve_alias(server, typed, context)?;
+            if name != "configure" && !commands_enabled(server, message, context)? { return Ok(None); }
+            name
+        },
+        None => typed.to_owned()
+    };
+    Ok(Some(Parsed { name, arguments: build_arguments(&rest[typed.len()..]) }))
+}
+
+fn commands_enabled(server: ServerId, message: &Message, context: &Context) -> Result<bool, Error> {
+    module::find("commands").unwrap().is_enabled_in(server, Some(message.channel_id), &context.store)
 }
 
 type PlugReference = Arc<Box<Plug + Send + Sync + 'static>>;
@@ -69,13 +103,18 @@ impl PlugSet {
         self.0.push(Arc::new(Box::new(plug)));
     }
 
+    /// Whether any plug, other than a fallback, provides the given command.
+    pub fn has_command(&self, name: &str) -> bool {
+        self.iter().any(|plug| !plug.is_fallback() && plug.matches_name(name))
+    }
+
     pub fn trigger_start(&self, context: &mut Context) -> Result<(), Error> {
         trace!("triggering start...");
         for plug in self.iter() {
             trace!("- Plug {:?}", plug);
             match plug.handle_start(context) {
                 Ok(PlugStatus::Continue) => { trace!("Continue."); }
-                Ok(PlugStatus::Stop) => { trace!("Break."); break; }
+                Ok(PlugStatus::Stop) | Ok(PlugStatus::Failed) => { trace!("Break."); break; }
                 Err(err) => return Err(err.into())
             }
         }
@@ -86,20 +125,52 @@ impl PlugSet {
     }
 
     pub fn trigger_event(&self, event: &Event, context: &mut Context) -> Result<(), Error> {
+        logging::set_server(util::event_server(event, context).map(|s| s.0));
         debug!("triggering event...");
         trace!("event: {:?}", event);
 
+        let parsed = match event {
+            &Event::MessageCreate(ref message) => match parse_command(message, context) {
+                Ok(parsed) => parsed.map(|parsed| (message, parsed)),
+                Err(ref err) if err.is_recoverable() => {
+                    warn!("Could not read the command in a message, so it is ignored: {:?}", err);
+                    metrics::error(err);
+                    None
+                },
+                Err(err) => {
+                    logging::set_server(None);
+                    return Err(err);
+                }
+            },
+            _ => None
+        };
+        let arguments = parsed.as_ref()
+            .map(|&(_, ref parsed)| parsed.arguments.iter().map(|s| &s[..]).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let command = parsed.as_ref()
+            .map(|&(message, ref parsed)| Command { name: &parsed.name, arguments: &arguments[..], message });
+
         for plug in self.iter() {
-            match plug.handle_event(event, context) {
+            let started = Instant::now();
+            let result = plug.handle_event(event, command.as_ref(), context);
+            metrics::plug(format!("{:?}", plug), started.elapsed());
+            if let Some(name) = context.dispatched.take() {
+                if !plug.is_fallback() { record_command(&name, &result, started, event, context); }
+            }
+
+            match result {
                 Ok(PlugStatus::Continue) => { trace!("{:?}: Continue.", plug); }
                 Ok(PlugStatus::Stop) => { trace!("{:?}: Break.", plug); break; }
+                Ok(PlugStatus::Failed) => { trace!("{:?}: Failed.", plug); break; }
                 Err(err) => {
                     warn!("{:?}: Error!", plug);
+                    metrics::error(&err);
                     if err.is_recoverable() {
                         warn!("Error is marked as recoverable, and so will be treated as a Continue.");
                         warn!("Error: {}, {:?}", err.description(), err);
                     } else {
                         error!("Error found in plug {:?}!", plug);
+                        logging::set_server(None);
                         return Err(err.into())
                     }
                 }
@@ -107,10 +178,91 @@ impl PlugSet {
         }
 
         debug!("event trigger done.");
+        logging::set_server(None);
         Ok(())
     }
 }
 
+fn record_command(name: &str, result: &PlugResult, started: Instant, event: &Event, context: &Context) {
+    let elapsed = started.elapsed();
+    let elapsed = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64;
+    let server = util::event_server(event, context).map(|s| s.0);
+    let retention = context.configuration.stats().retention;
+    let success = match result { &Ok(PlugStatus::Failed) | &Err(_) => false, _ => true };
+    metrics::command(name, success);
+
+    if let Err(err) = context.store.stats_record(server, name, success, elapsed, retention) {
+        warn!("Could not record usage of command {}: {:?}", name, err);
+    }
+}
+
+/// Static configuration for a plug, read from the `[plugs.<NAME>]` table of the configuration
+/// file.  Each type must be listed in `plugs::configs`, and can then be read from any plug with
+/// `Context::plug_config`.
+pub trait PlugConfig: Serialize + DeserializeOwned + Default + Any + Send + Sync {
+    /// The name of the table the configuration is read from.
+    const NAME: &'static str;
+    /// Checks the configuration, returning each problem with its key relative to the table.
+    fn validate(&self) -> Vec<Diagnostic> { vec![] }
+}
+
+/// Something that is called with each plug configuration type; see `plugs::configs`.
+pub trait PlugConfigVisitor {
+    fn visit<T: PlugConfig>(&mut self);
+}
+
+/// The parsed configuration of every plug.
+#[derive(Clone, Default)]
+pub struct PlugConfigs(BTreeMap<&'static str, Arc<Any + Send + Sync>>);
+
+impl PlugConfigs {
+    pub fn get<T: PlugConfig>(&self) -> &T {
+        self.0.get(T::NAME).and_then(|c| c.downcast_ref::<T>())
+            .unwrap_or_else(|| panic!("plug configuration {} was not loaded", T::NAME))
+    }
+}
+
+/// Loads each plug configuration from the configuration file, collecting any problems.
+pub struct PlugConfigLoader<'a> {
+    pub configuration: &'a Configuration,
+    pub configs: PlugConfigs,
+    pub problems: Vec<Diagnostic>,
+}
+
+impl<'a> PlugConfigVisitor for PlugConfigLoader<'a> {
+    fn visit<T: PlugConfig>(&mut self) {
+        let parsed = match self.configuration.plug(T::NAME) {
+            Some(table) => table.clone().try_into::<T>(),
+            None => Ok(T::default())
+        };
+
+        match parsed {
+            Ok(config) => {
+                for mut problem in config.validate() {
+                    problem.key = format!("plugs.{}.{}", T::NAME, problem.key);
+                    self.problems.push(problem);
+                }
+                self.configs.0.insert(T::NAME, Arc::new(config));
+            },
+            Err(e) => self.problems.push(Diagnostic::new(&format!("plugs.{}", T::NAME),
+                format!("could not be parsed: {}", e), "check the types of the values in the table"))
+        }
+    }
+}
+
+/// Collects the default table for each plug configuration.
+#[derive(Default)]
+pub struct PlugConfigDefaults(pub BTreeMap<String, toml::Value>);
+
+impl PlugConfigVisitor for PlugConfigDefaults {
+    fn visit<T: PlugConfig>(&mut self) {
+        match toml::Value::try_from(T::default()) {
+            Ok(table) => { self.0.insert(T::NAME.to_owned(), table); },
+            Err(e) => warn!("Could not serialize the default configuration for {}: {}", T::NAME, e)
+        }
+    }
+}
+
 impl Default for PlugSet {
     fn default() -> PlugSet { PlugSet(Vec::new()) }
 }
@@ -2,16 +2,18 @@ use shard::Context;
 use shard::plug::{Command, PlugSet, PlugStatus};
 use error::Error;
 use discord::model::{ServerId, ChannelId};
-use shard::plugs::configuration::module;
+use shard::plugs::configuration::{module, setting};
 use shard::util;
+use store::Scope;
 
 pub fn log(server: ServerId, action: &str, context: &mut Context, options: Option<&[(&str, &str)]>) -> Result<(), Error> {
     let module = module::find("admin.log").unwrap();
-    if !module.is_enabled(server, context)? { return Ok(()) }
-    let channel: Option<String> = context.store.setting_get(server.0, "admin.log.channel")?;
-    match channel {
+    if !module.is_enabled(server, &context.store)? { return Ok(()) }
+    // This is read without reporting a corrupt value, since the report would come back here.
+    let channel = setting::find("admin.log.channel").unwrap().read(server, Scope::Server, &context.store)?;
+    match channel.and_then(|c| c.id()) {
         Some(channel) => {
-            util::send_embed(ChannelId(channel.parse()?), context, |e| {
+            util::send_embed(ChannelId(channel), context, |e| {
                 e.description(action)
                     .fields(|mut e| {
                         if let Some(o) = options { for &(n, v) in o { e = e.field(n, v, false) } }
@@ -1,4 +1,4 @@
-mod log;
+pub(super) mod log;
 use shard::plug::PlugSet;
 
 pub(super) fn init(set: &mut PlugSet) {
@@ -1,6 +1,8 @@
 use shard::Context;
-use shard::plug::{Plug, PlugSet, PlugStatus, PlugResult};
+use shard::plug::{Command, Plug, PlugConfig, PlugSet, PlugStatus, PlugResult};
+use shard::plugs::configuration::{module, setting};
 use shard::util;
+use configuration::Diagnostic;
 use error::Error;
 use rand;
 
@@ -8,34 +10,63 @@ use discord::model::{Event, ChannelId, ServerId};
 
 static DEFAULT_MESSAGE: &'static str = "User {user} has joined the server!";
 
+/// The `[plugs.comfort]` table.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ComfortConfig {
+    /// The join message used when a server hasn't set any of its own.  `{user}` is replaced
+    /// with a mention of the new member.
+    pub default_join_message: String,
+}
+
+impl Default for ComfortConfig {
+    fn default() -> ComfortConfig { ComfortConfig { default_join_message: DEFAULT_MESSAGE.into() } }
+}
+
+impl PlugConfig for ComfortConfig {
+    const NAME: &'static str = "comfort";
+
+    fn validate(&self) -> Vec<Diagnostic> {
+        if self.default_join_message.trim().is_empty() {
+            vec![Diagnostic::new("default_join_message", "the default join message is empty".into(),
+                "remove the key to use the built-in message")]
+        } else { vec![] }
+    }
+}
+
+/// The channel join messages are sent to.  The server's `comfort.join.channel` may be
+/// redirected by setting it again for that channel, e.g. while it's locked.
 fn join_message_channel(server: ServerId, context: &mut Context) -> Result<Option<ChannelId>, Error> {
-    let id: Option<u64> = context.store
-        .setting_get(server.0, "comfort.join.channel")
-        .map_err(|e| -> Error { e.into() })?
-        .and_then(|d: Option<String>| d)
-        .and_then(|d: String| d.parse().ok());
+    let channel = match setting::value("comfort.join.channel", server, context)?.and_then(|v| v.id()) {
+        Some(channel) => ChannelId(channel),
+        None => return Ok(None)
+    };
+    let id = setting::value_in("comfort.join.channel", server, Some(channel), None, context)?.and_then(|v| v.id());
     Ok(id.map(|v| ChannelId(v)))
 }
 
-fn join_message_value(server: ServerId, context: &mut Context) -> Result<String, Error> {
-    let messages = context.store.setting_get_array(server.0, "comfort.join.message")?;
+/// A join message for the channel it's sent in, picked from `comfort.join.message`.
+fn join_message_value(server: ServerId, channel: ChannelId, context: &mut Context) -> Result<String, Error> {
+    let messages = setting::value_in("comfort.join.message", server, Some(channel), None, context)?
+        .and_then(|v| v.array().map(|a| a.to_vec())).unwrap_or_default();
     if messages.len() > 0 {
         Ok(rand::sample(&mut rand::thread_rng(), messages, 1).remove(0))
     } else {
-        Ok(DEFAULT_MESSAGE.into())
+        Ok(context.plug_config::<ComfortConfig>().default_join_message.clone())
     }
 }
 
 plug! { JoinMessage => {
-    fn handle_event(&self, event: &Event, context: &mut Context) -> PlugResult {
+    fn handle_event(&self, event: &Event, _: Option<&Command>, context: &mut Context) -> PlugResult {
         match event {
             &Event::ServerMemberAdd(server, ref member) => {
                 debug!("Found member add event!");
-                if context.store.module_check_enabled(server.0, "comfort.join", false)? {
-                    debug!("Module enabled, checking channel...");
-                    if let Some(channel) = join_message_channel(server, context)? {
-                        debug!("Found channel, running!");
-                        let join_message = join_message_value(server, context)?
+                let module = module::find("comfort.join").unwrap();
+                if let Some(channel) = join_message_channel(server, context)? {
+                    debug!("Found channel, checking module...");
+                    if module.is_enabled_in(server, Some(channel), &context.store)? {
+                        debug!("Module enabled, running!");
+                        let join_message = join_message_value(server, channel, context)?
                             .replace("{user}", &member.user.mention().to_string());
                         util::send_info_embed(&join_message, channel, context)?;
                     }
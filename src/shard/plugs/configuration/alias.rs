@@ -0,0 +1,63 @@
+use shard::Context;
+use shard::plug::Command;
+use shard::util;
+use discord::model::ServerId;
+use super::ConfigureError;
+
+pub(super) fn set(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
+    let alias = command.arguments.get(1).ok_or(ConfigureError::InvalidArgumentError(2))?;
+    let target = command.arguments.get(2).ok_or(ConfigureError::InvalidArgumentError(3))?;
+    let server: ServerId = util::server_for(command.message.channel_id, context)
+        .ok_or(ConfigureError::NonPublicError)?;
+
+    if alias.is_empty() || alias.contains(char::is_whitespace) {
+        return Err(ConfigureError::InvalidArgumentError(2));
+    }
+
+    if context.shard.plugs.has_command(alias) {
+        return Err(ConfigureError::Rejected(
+            format!("`{}` is already a command, and can't be used as an alias.", alias)));
+    }
+
+    let chain = util::alias_chain(server, target, context).map_err(|e| ConfigureError::Error(e))?;
+    if chain.iter().any(|name| name == alias) {
+        return Err(ConfigureError::Rejected(
+            format!("Aliasing `{}` to `{}` would create a cycle: `{}`.", alias, target,
+                chain.join("` -> `"))));
+    }
+
+    if !context.shard.plugs.has_command(chain.last().unwrap()) {
+        return Err(ConfigureError::Rejected(format!("`{}` is not a command.", target)));
+    }
+
+    context.store.alias_set(server.0, alias, target).map_err(|e| ConfigureError::Error(e))?;
+    util::send_success_embed(&format!("`{}` is now an alias for `{}`.", alias, target),
+        command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e))
+}
+
+pub(super) fn remove(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
+    let alias = command.arguments.get(1).ok_or(ConfigureError::InvalidArgumentError(2))?;
+    let server: ServerId = util::server_for(command.message.channel_id, context)
+        .ok_or(ConfigureError::NonPublicError)?;
+    let removed = context.store.alias_remove(server.0, alias).map_err(|e| ConfigureError::Error(e))?;
+
+    if !removed { return Err(ConfigureError::Rejected(format!("`{}` is not an alias.", alias))); }
+    util::send_success_embed(&format!("Alias `{}` was removed.", alias),
+        command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e))
+}
+
+pub(super) fn list(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
+    let server: ServerId = util::server_for(command.message.channel_id, context)
+        .ok_or(ConfigureError::NonPublicError)?;
+    let mut aliases = context.store.alias_list(server.0).map_err(|e| ConfigureError::Error(e))?
+        .into_iter().collect::<Vec<_>>();
+    aliases.sort();
+
+    if aliases.is_empty() {
+        util::send_info_embed("There are no aliases on this server.", command.message.channel_id, context)
+    } else {
+        let body = aliases.iter().map(|&(ref alias, ref target)| format!("`{}` -> `{}`", alias, target))
+            .collect::<Vec<_>>().join("\n");
+        util::send_info_embed(&body, command.message.channel_id, context)
+    }.map(|_| ()).map_err(|e| ConfigureError::Error(e))
+}
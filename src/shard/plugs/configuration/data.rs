@@ -0,0 +1,50 @@
+use shard::Context;
+use shard::plug::{Command, Plug, PlugSet, PlugStatus, PlugResult};
+use shard::util;
+use scheduler::{self, Job};
+use discord::model::{Event, PossibleServer, ServerId};
+use super::ConfigureError;
+
+static SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+plug! { Lifecycle => {
+    fn handle_event(&self, event: &Event, _: Option<&Command>, context: &mut Context) -> PlugResult {
+        match event {
+            // An offline server is only unavailable, e.g. during an outage; the bot is still in it.
+            &Event::ServerDelete(PossibleServer::Online(ref server)) => {
+                let grace = context.configuration.data().grace;
+                scheduler::schedule(Job::Purge(server.id), grace as u64 * SECONDS_PER_DAY, &context.store)?;
+                info!("Left server {}; its data will be purged in {} day(s) unless the bot is added back.", server.id, grace);
+            },
+            &Event::ServerCreate(PossibleServer::Online(ref server)) => {
+                if scheduler::cancel(Job::Purge(server.id), &context.store)? {
+                    info!("Added back to server {} in time; its data was kept.", server.id);
+                }
+            },
+            _ => {}
+        }
+        Ok(PlugStatus::Continue)
+    }
+} }
+
+/// `configure data.purge confirm`: deletes everything stored for the server at once.
+pub(super) fn purge(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
+    let server: ServerId = util::server_for(command.message.channel_id, context)
+        .ok_or(ConfigureError::NonPublicError)?;
+    if !util::is_server_admin(command.message.channel_id, command.message.author.id, context) {
+        return Err(ConfigureError::Rejected("Only server administrators may purge the server's data.".into()));
+    }
+    if command.arguments.get(1) != Some(&"confirm") {
+        return Err(ConfigureError::Rejected("This deletes every module flag, setting, alias, and usage statistic \
+            for this server, and can't be undone.  Use `configure data.purge confirm` to go ahead.".into()));
+    }
+
+    let deleted = context.store.server_purge(server.0).map_err(|e| ConfigureError::Error(e))?;
+    scheduler::cancel(Job::Purge(server), &context.store).map_err(|e| ConfigureError::Error(e))?;
+    util::send_success_embed(&format!("Deleted {} key(s); every module and setting is back to its default.", deleted),
+        command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e))
+}
+
+pub(super) fn init(set: &mut PlugSet) {
+    set.push(Lifecycle);
+}
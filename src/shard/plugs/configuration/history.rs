@@ -0,0 +1,119 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use shard::Context;
+use shard::plug::Command;
+use shard::plugs::administration::log;
+use shard::util;
+use store::{Record, Scope};
+use discord::model::ServerId;
+use super::{module, setting, ConfigureError};
+
+/// How many changes are shown per page of `configure history`.
+static PAGE_SIZE: usize = 10;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// How long ago a time was, roughly.
+fn ago(time: u64) -> String {
+    match now().saturating_sub(time) {
+        elapsed if elapsed < 60 => String::from("just now"),
+        elapsed if elapsed < 60 * 60 => format!("{}m ago", elapsed / 60),
+        elapsed if elapsed < 24 * 60 * 60 => format!("{}h ago", elapsed / (60 * 60)),
+        elapsed => format!("{}d ago", elapsed / (24 * 60 * 60))
+    }
+}
+
+/// What a change did, e.g. "disabled module `admin.log`".
+fn describe(record: &Record) -> String {
+    let scope = record.scope.map(setting::describe).unwrap_or_else(|| String::from("this server"));
+    match &record.action[..] {
+        "module.enable" => format!("enabled module `{}` for {}", record.name, scope),
+        "module.disable" => format!("disabled module `{}` for {}", record.name, scope),
+        "module.inherit" => format!("cleared module `{}` for {}", record.name, scope),
+        "setting.set" => format!("set `{}` for {}", record.name, scope),
+        "setting.push" => format!("added to `{}` for {}", record.name, scope),
+        "setting.clear" => format!("cleared `{}` for {}", record.name, scope),
+        action => format!("ran `{}` on `{}`", action, record.name)
+    }
+}
+
+/// Who made a change; changes made through the admin API without a user are recorded as
+/// made by user 0.
+fn actor(record: &Record) -> String {
+    if record.actor == 0 { String::from("The admin API") } else { format!("<@{}>", record.actor) }
+}
+
+fn shown(value: &Option<String>) -> String {
+    match value { &Some(ref value) => format!("`{}`", value), &None => String::from("unset") }
+}
+
+/// Records a change made by a `configure` command in the server's history, and mirrors it to
+/// the server's administration log.  The change has already been made, so a failure here is
+/// logged rather than reported to the author.
+pub(super) fn record(server: ServerId, command: &Command, name: &str, scope: Option<Scope>,
+                     old: Option<String>, new: Option<String>, context: &mut Context) {
+    let record = Record {
+        time: now(),
+        actor: command.message.author.id.0,
+        action: command.arguments.get(0).map(|a| a.to_string()).unwrap_or_default(),
+        name: name.to_owned(),
+        scope, old, new
+    };
+    if let Err(e) = context.store.history_record(server.0, &record) {
+        warn!("Could not record {:?} in the history of server {}: {:?}", record, server, e);
+    }
+
+    let (old, new) = (shown(&record.old), shown(&record.new));
+    let action = format!("{} {}.", actor(&record), describe(&record));
+    if let Err(e) = log::log(server, &action, context, Some(&[("Old", &old[..]), ("New", &new[..])][..])) {
+        warn!("Could not log a configuration change for server {}: {:?}", server, e);
+    }
+}
+
+/// `configure history [name] [page]`: the changes made to the server's modules and settings,
+/// newest first, optionally only those to the named module or setting.
+pub(super) fn show(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
+    let server: ServerId = util::server_for(command.message.channel_id, context)
+        .ok_or(ConfigureError::NonPublicError)?;
+    if !util::is_server_admin(command.message.channel_id, command.message.author.id, context) {
+        return Err(ConfigureError::Rejected("Only server administrators may view the configuration history.".into()));
+    }
+
+    let (name, position) = match command.arguments.get(1) {
+        Some(argument) if argument.parse::<usize>().is_err() => {
+            if setting::find(argument).is_none() && module::find(argument).is_none() {
+                return Err(ConfigureError::InvalidArgumentError(2));
+            }
+            (Some(*argument), 2)
+        },
+        _ => (None, 1)
+    };
+    let page = match command.arguments.get(position) {
+        Some(argument) => match argument.parse::<usize>() {
+            Ok(page) if page > 0 => page,
+            _ => return Err(ConfigureError::InvalidArgumentError(position + 1))
+        },
+        None => 1
+    };
+
+    let records = context.store.history_read(server.0).map_err(|e| ConfigureError::Error(e))?.into_iter()
+        .filter(|record| name.map(|name| record.name == name).unwrap_or(true))
+        .collect::<Vec<_>>();
+    let pages = (records.len() + PAGE_SIZE - 1) / PAGE_SIZE;
+    if records.is_empty() {
+        let subject = name.map(|name| format!("`{}`", name)).unwrap_or_else(|| String::from("this server"));
+        return util::send_info_embed(&format!("No changes to {} have been recorded.", subject),
+            command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e));
+    }
+    if page > pages {
+        return Err(ConfigureError::Rejected(format!("There are only {} page(s) of changes.", pages)));
+    }
+
+    let mut body = records.iter().skip((page - 1) * PAGE_SIZE).take(PAGE_SIZE)
+        .map(|record| format!("**{}** {} {}: {} -> {}", ago(record.time), actor(record), describe(record),
+            shown(&record.old), shown(&record.new)))
+        .collect::<Vec<_>>().join("\n");
+    body.push_str(&format!("\n\nPage {} of {}.", page, pages));
+    util::send_info_embed(&body, command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e))
+}
@@ -3,8 +3,12 @@ use shard::plug::{Command, Plug, PlugSet, PlugStatus, PlugResult};
 use shard::util;
 use ::error::Error;
 
-pub(super) mod module;
-pub(super) mod setting;
+pub(super) mod alias;
+mod data;
+mod history;
+pub(crate) mod module;
+pub(crate) mod setting;
+mod transfer;
 
 // TODO: struct Module
 // TODO: struct Setting (kind Channel, User, Value, Array)
@@ -18,6 +22,7 @@ enum ConfigureError {
     InvalidArgumentError(usize),
     NonPublicError,
     FormatError,
+    Rejected(String),
     Error(Error),
 }
 
@@ -27,28 +32,40 @@ plug! { Configure => {
         let result = match command.arguments.get(0) {
             Some(&"module.enable")   => module::enable(command, context),
             Some(&"module.disable")  => module::disable(command, context),
+            Some(&"module.inherit")  => module::inherit(command, context),
             Some(&"module.enabled?") => module::check(command, context),
             Some(&"setting.set")     => setting::set(command, context),
             Some(&"setting.get")     => setting::get(command, context),
             Some(&"setting.clear")   => setting::clear(command, context),
             Some(&"setting.push")    => setting::push(command, context),
+            Some(&"alias.set")       => alias::set(command, context),
+            Some(&"alias.remove")    => alias::remove(command, context),
+            Some(&"alias.list")      => alias::list(command, context),
+            Some(&"export")          => transfer::export(command, context),
+            Some(&"import")          => transfer::import(command, context),
+            Some(&"data.purge")      => data::purge(command, context),
+            Some(&"history")         => history::show(command, context),
             _ => Err(ConfigureError::InvalidArgumentError(1))
         };
 
         match result {
             Err(ConfigureError::InvalidArgumentError(position)) => {
                 util::send_incorrect_argument(position, command.message.channel_id, context)?;
-                Ok(PlugStatus::Stop)
+                Ok(PlugStatus::Failed)
             },
             Err(ConfigureError::NonPublicError) => {
                 util::send_must_public(command.message.channel_id, context)?;
-                Ok(PlugStatus::Stop)
+                Ok(PlugStatus::Failed)
             },
             Err(ConfigureError::FormatError) => {
                 util::send_error_embed("Incorrect format for setting value!",
                     command.message.channel_id, context)?;
-                Ok(PlugStatus::Stop)
-            }
+                Ok(PlugStatus::Failed)
+            },
+            Err(ConfigureError::Rejected(reason)) => {
+                util::send_error_embed(&reason, command.message.channel_id, context)?;
+                Ok(PlugStatus::Failed)
+            },
             Err(ConfigureError::Error(err)) => Err(err),
             Ok(_) => Ok(PlugStatus::Stop)
         }
@@ -57,4 +74,5 @@ plug! { Configure => {
 
 pub(super) fn init(set: &mut PlugSet) {
     set.push(Configure);
+    data::init(set);
 }
@@ -4,9 +4,10 @@ use std::collections::BTreeSet;
 use shard::Context;
 use shard::plug::Command;
 use shard::util;
+use store::{Scope, Store};
 use error::Error;
-use discord::model::ServerId;
-use super::ConfigureError;
+use discord::model::{ChannelId, ServerId};
+use super::{history, setting, ConfigureError};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 // name, default
@@ -15,22 +16,45 @@ pub struct Module(&'static str, bool);
 static MODULES: &'static [&'static Module] = &[
     &Module("test", false),
     &Module("utility.join", false),
-    &Module("admin.log", true)
+    &Module("admin.log", true),
+    &Module("comfort.join", false),
+    &Module("commands", true)
 ];
 
 impl Module {
-    pub fn enable(&self, server: ServerId, context: &mut Context) -> Result<(), Error> {
-        if self.1 { context.store.module_clear(server.0, self.0)  }
-        else { context.store.module_enable(server.0, self.0) }
+    pub fn name(&self) -> &'static str { self.0 }
+
+    /// Turns the module on for the server, or for a channel in it.  A channel's flag is always
+    /// stored, so that it outlasts later changes to the server's, until it is given up through
+    /// `inherit`; the server's is only stored when it differs from the default.
+    pub fn enable(&self, server: ServerId, scope: Scope, store: &Store) -> Result<(), Error> {
+        self.turn(server, scope, true, store)
+    }
+
+    pub fn disable(&self, server: ServerId, scope: Scope, store: &Store) -> Result<(), Error> {
+        self.turn(server, scope, false, store)
+    }
+
+    fn turn(&self, server: ServerId, scope: Scope, enabled: bool, store: &Store) -> Result<(), Error> {
+        if scope == Scope::Server && enabled == self.1 { store.module_clear(server.0, scope, self.0) }
+        else if enabled { store.module_enable(server.0, scope, self.0) }
+        else { store.module_disable(server.0, scope, self.0) }
     }
 
-    pub fn disable(&self, server: ServerId, context: &mut Context) -> Result<(), Error> {
-        if !self.1 { context.store.module_clear(server.0, self.0)  }
-        else { context.store.module_disable(server.0, self.0) }
+    /// Clears the module's flag in the scope, so that a channel follows the server again, and
+    /// the server the default.
+    pub fn inherit(&self, server: ServerId, scope: Scope, store: &Store) -> Result<(), Error> {
+        store.module_clear(server.0, scope, self.0)
     }
 
-    pub fn is_enabled(&self, server: ServerId, context: &mut Context) -> Result<bool, Error> {
-        context.store.module_check_enabled(server.0, self.0, self.1)
+    /// Whether the module is on for the server as a whole.
+    pub fn is_enabled(&self, server: ServerId, store: &Store) -> Result<bool, Error> {
+        store.module_check_enabled(server.0, None, self.0, self.1)
+    }
+
+    /// Whether the module is on in the channel, which may override the server.
+    pub fn is_enabled_in(&self, server: ServerId, channel: Option<ChannelId>, store: &Store) -> Result<bool, Error> {
+        store.module_check_enabled(server.0, channel.map(|c| c.0), self.0, self.1)
     }
 }
 
@@ -50,40 +74,116 @@ pub fn find(name: &str) -> Option<&'static Module> {
     MODULE_TREE.get(name).map(|m| *m)
 }
 
-pub(super) fn enable(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
+/// A module's state, as it is recorded in the history.
+pub(crate) fn state(enabled: bool) -> String { String::from(if enabled { "enabled" } else { "disabled" }) }
+
+/// The module, server and scope a `module.*` command names: `<name> [scope]`, where the
+/// scope is the server (the default) or a channel in it.
+fn target(command: &Command, context: &Context) -> Result<(&'static Module, ServerId, Scope), ConfigureError> {
     let module: &Module = command.arguments.get(1).and_then(|name| find(name))
         .ok_or(ConfigureError::InvalidArgumentError(2))?;
     let server: ServerId = util::server_for(command.message.channel_id, context)
         .ok_or(ConfigureError::NonPublicError)?;
+    let scope = match command.arguments.get(2) {
+        Some(argument) => setting::scope(argument, server, command, context)?,
+        None => Scope::Server
+    };
+    if let Scope::User(_) = scope {
+        return Err(ConfigureError::Rejected(format!("Module {} can't be turned on or off for a user.", module.0)));
+    }
+    Ok((module, server, scope))
+}
 
-    module.enable(server, context).map_err(|e| ConfigureError::Error(e))?;
-    util::send_success_embed(&format!("Module {} was enabled.", module.0),
+/// The flag stored in exactly the scope, as it is recorded in the history; `None` if unset.
+fn stored(module: &Module, server: ServerId, scope: Scope, store: &Store) -> Result<Option<String>, ConfigureError> {
+    store.module_is_enabled(server.0, scope, module.0).map(|flag| flag.map(state)).map_err(|e| ConfigureError::Error(e))
+}
+
+/// Whether the module is on in the scope, as it would apply there.
+fn enabled_in(module: &Module, server: ServerId, scope: Scope, store: &Store) -> Result<bool, ConfigureError> {
+    let channel = match scope { Scope::Channel(channel) => Some(ChannelId(channel)), _ => None };
+    module.is_enabled_in(server, channel, store).map_err(|e| ConfigureError::Error(e))
+}
+
+/// `configure module.enable <name> [scope]`.
+pub(super) fn enable(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
+    let (module, server, scope) = target(command, context)?;
+    let old = stored(module, server, scope, &context.store)?;
+    module.enable(server, scope, &context.store).map_err(|e| ConfigureError::Error(e))?;
+    let new = stored(module, server, scope, &context.store)?;
+    history::record(server, command, module.0, Some(scope), old, new, context);
+    util::send_success_embed(&format!("Module {} was enabled for {}.", module.0, setting::describe(scope)),
         command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e))
 }
 
+/// `configure module.disable <name> [scope]`.
 pub(super) fn disable(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
-    let module: &Module = command.arguments.get(1).and_then(|name| find(name))
-        .ok_or(ConfigureError::InvalidArgumentError(2))?;
-    let server: ServerId = util::server_for(command.message.channel_id, context)
-        .ok_or(ConfigureError::NonPublicError)?;
+    let (module, server, scope) = target(command, context)?;
+    let old = stored(module, server, scope, &context.store)?;
+    module.disable(server, scope, &context.store).map_err(|e| ConfigureError::Error(e))?;
+    let new = stored(module, server, scope, &context.store)?;
+    history::record(server, command, module.0, Some(scope), old, new, context);
+    util::send_success_embed(&format!("Module {} was disabled for {}.", module.0, setting::describe(scope)),
+        command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e))
+}
 
-    module.disable(server, context).map_err(|e| ConfigureError::Error(e))?;
-    util::send_success_embed(&format!("Module {} was disabled.", module.0),
+/// `configure module.inherit <name> [scope]`.
+pub(super) fn inherit(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
+    let (module, server, scope) = target(command, context)?;
+    let old = stored(module, server, scope, &context.store)?;
+    module.inherit(server, scope, &context.store).map_err(|e| ConfigureError::Error(e))?;
+    history::record(server, command, module.0, Some(scope), old, None, context);
+    let enabled = enabled_in(module, server, scope, &context.store)?;
+    util::send_success_embed(&format!("Module {} now follows {} for {}, and is {}.", module.0,
+        if scope == Scope::Server { "the default" } else { "the server" }, setting::describe(scope), state(enabled)),
         command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e))
 }
 
+/// `configure module.enabled? <name> [scope]`.
 pub(super) fn check(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
-    let module: &Module = command.arguments.get(1).and_then(|name| find(name))
-        .ok_or(ConfigureError::InvalidArgumentError(2))?;
-    let server: ServerId = util::server_for(command.message.channel_id, context)
-        .ok_or(ConfigureError::NonPublicError)?;
-    let enabled = module.is_enabled(server, context).map_err(|e| ConfigureError::Error(e))?;
+    let (module, server, scope) = target(command, context)?;
+    let enabled = enabled_in(module, server, scope, &context.store)?;
 
     if enabled {
-        util::send_success_embed(&format!("Module {} is enabled.", module.0),
+        util::send_success_embed(&format!("Module {} is enabled for {}.", module.0, setting::describe(scope)),
             command.message.channel_id, context)
     } else {
-        util::send_success_embed(&format!("Module {} is disabled.", module.0),
+        util::send_success_embed(&format!("Module {} is disabled for {}.", module.0, setting::describe(scope)),
             command.message.channel_id, context)
     }.map(|_| ()).map_err(|e| ConfigureError::Error(e))
 }
+
+#[cfg(test)]
+mod tests {
+    use configuration::Bot;
+    use discord::model::{ChannelId, ServerId};
+    use store::{Scope, Store};
+    use super::find;
+
+    fn memory() -> Store { Store::from(&Bot { store: String::from("sqlite://:memory:"), ..Bot::default() }).unwrap() }
+
+    #[test]
+    fn channel_flags_outlast_changes_to_the_server() {
+        let (store, server, channel) = (memory(), ServerId(1), ChannelId(2));
+        let module = find("test").unwrap();
+        module.enable(server, Scope::Server, &store).unwrap();
+        module.enable(server, Scope::Channel(channel.0), &store).unwrap();
+        assert_eq!(store.module_is_enabled(server.0, Scope::Channel(channel.0), "test").unwrap(), Some(true));
+
+        module.disable(server, Scope::Server, &store).unwrap();
+        assert!(!module.is_enabled(server, &store).unwrap());
+        assert!(module.is_enabled_in(server, Some(channel), &store).unwrap());
+
+        module.inherit(server, Scope::Channel(channel.0), &store).unwrap();
+        assert!(!module.is_enabled_in(server, Some(channel), &store).unwrap());
+    }
+
+    #[test]
+    fn server_flags_matching_the_default_are_not_stored() {
+        let (store, server) = (memory(), ServerId(1));
+        let module = find("test").unwrap();
+        module.enable(server, Scope::Server, &store).unwrap();
+        module.disable(server, Scope::Server, &store).unwrap();
+        assert_eq!(store.module_is_enabled(server.0, Scope::Server, "test").unwrap(), None);
+    }
+}
@@ -1,95 +1,299 @@
 use std::cmp::Ordering;
 use std::borrow::Borrow;
 use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Error as FmtError};
+use serde_json;
 use shard::Context;
 use shard::plug::Command;
+use shard::plugs::administration::log;
 use shard::util;
+use store::{Entry, Scope, ScopeKind, Store};
 use error::Error;
-use discord::model::ServerId;
-use super::ConfigureError;
+use discord::model::{ChannelId, ServerId, UserId};
+use super::{history, ConfigureError};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum SettingKind {
     Channel, User, Role, String, Integer, Array
 }
 
+/// A setting's value, as it is stored: serialized with its kind, so that a value can be
+/// checked against the setting it is read for.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum SettingValue {
+    Channel(u64),
+    User(u64),
+    Role(u64),
+    String(String),
+    Integer(u64),
+    Array(Vec<String>),
+}
+
+/// The largest id or integer a setting may hold, since a sqlite store keeps them as `i64`s.
+const MAX_NUMBER: u64 = ::std::i64::MAX as u64;
+
+impl SettingValue {
+    pub fn kind(&self) -> SettingKind {
+        match self {
+            &SettingValue::Channel(_) => SettingKind::Channel,
+            &SettingValue::User(_) => SettingKind::User,
+            &SettingValue::Role(_) => SettingKind::Role,
+            &SettingValue::String(_) => SettingKind::String,
+            &SettingValue::Integer(_) => SettingKind::Integer,
+            &SettingValue::Array(_) => SettingKind::Array
+        }
+    }
+
+    /// The id in a channel, user, or role value.
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            &SettingValue::Channel(id) | &SettingValue::User(id) | &SettingValue::Role(id) => Some(id),
+            _ => None
+        }
+    }
+
+    pub fn array(&self) -> Option<&[String]> {
+        match self { &SettingValue::Array(ref values) => Some(&values[..]), _ => None }
+    }
+
+    /// Whether every store can hold the value; see `MAX_NUMBER`.
+    fn fits(&self) -> bool {
+        match self {
+            &SettingValue::Integer(value) => value <= MAX_NUMBER,
+            value => value.id().map(|id| id <= MAX_NUMBER).unwrap_or(true)
+        }
+    }
+}
+
+/// The value, if every store can hold it.
+fn fitting(value: SettingValue) -> Option<SettingValue> {
+    if value.fits() { Some(value) } else { None }
+}
+
+impl Display for SettingValue {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            &SettingValue::Channel(id) => write!(f, "<#{}>", id),
+            &SettingValue::User(id) => write!(f, "<@{}>", id),
+            &SettingValue::Role(id) => write!(f, "<@&{}>", id),
+            &SettingValue::String(ref value) => write!(f, "{}", value),
+            &SettingValue::Integer(value) => write!(f, "{}", value),
+            &SettingValue::Array(ref values) => write!(f, "{:?}", values)
+        }
+    }
+}
+
+/// What was found when reading a setting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stored {
+    Unset,
+    Value(SettingValue),
+    /// The stored value couldn't be read as the setting's kind; holds the raw value, and why.
+    Corrupt(String, String),
+}
+
+/// What became of a push onto a setting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pushed {
+    Done,
+    /// The setting isn't an array.
+    WrongKind,
+    /// The stored value isn't an array, and was left alone; holds the raw value.
+    Corrupt(String),
+}
+
+/// The scopes a setting may be set in; every setting may be set for the whole server.
+const SERVER_ONLY: &'static [ScopeKind] = &[ScopeKind::Server];
+const CHANNEL: &'static [ScopeKind] = &[ScopeKind::Server, ScopeKind::Channel];
+const USER: &'static [ScopeKind] = &[ScopeKind::Server, ScopeKind::User];
+const ANY: &'static [ScopeKind] = &[ScopeKind::Server, ScopeKind::Channel, ScopeKind::User];
+
 #[derive(Debug, Copy, Clone)]
-// name, default
-pub struct Setting(&'static str, SettingKind);
+// name, kind, scopes
+pub struct Setting(&'static str, SettingKind, &'static [ScopeKind]);
 
 static SETTINGS: &'static [&'static Setting] = &[
-    &Setting("test.channel", SettingKind::Channel),
-    &Setting("test.user", SettingKind::User),
-    &Setting("test.role", SettingKind::Role),
-    &Setting("test.str", SettingKind::String),
-    &Setting("test.int", SettingKind::Integer),
-    &Setting("test.ary", SettingKind::Array),
-    &Setting("comfort.join.channel", SettingKind::Channel),
-    &Setting("comfort.join.message", SettingKind::Array)
+    &Setting("test.channel", SettingKind::Channel, CHANNEL),
+    &Setting("test.user", SettingKind::User, USER),
+    &Setting("test.role", SettingKind::Role, SERVER_ONLY),
+    &Setting("test.str", SettingKind::String, ANY),
+    &Setting("test.int", SettingKind::Integer, ANY),
+    &Setting("test.ary", SettingKind::Array, ANY),
+    &Setting("admin.log.channel", SettingKind::Channel, SERVER_ONLY),
+    &Setting("comfort.join.channel", SettingKind::Channel, CHANNEL),
+    &Setting("comfort.join.message", SettingKind::Array, CHANNEL)
 ];
 
 impl Setting {
-    pub fn get(&self, server: ServerId, context: &mut Context) -> Result<Option<String>, Error> {
-        match self.1 {
+    pub fn name(&self) -> &'static str { self.0 }
+    pub fn kind(&self) -> SettingKind { self.1 }
+    pub fn scopes(&self) -> &'static [ScopeKind] { self.2 }
+    pub fn allows(&self, scope: Scope) -> bool { self.2.contains(&scope.kind()) }
+
+    fn check_scope(&self, scope: Scope) -> Result<(), Error> {
+        if self.allows(scope) { Ok(()) } else {
+            Err(Error::ConfigurationError(format!("setting {} can't be set per {:?}", self.0, scope.kind())))
+        }
+    }
+
+    /// Checks a stored value against the setting's kind.
+    pub(crate) fn decode(&self, raw: Option<String>) -> Stored {
+        match raw {
+            None => Stored::Unset,
+            Some(raw) => match serde_json::from_str::<SettingValue>(&raw) {
+                Ok(ref value) if value.kind() != self.1 =>
+                    Stored::Corrupt(raw.clone(), format!("expected a {:?}, but found a {:?}", self.1, value.kind())),
+                Ok(ref value) if !value.fits() =>
+                    Stored::Corrupt(raw.clone(), format!("{} is larger than {}", value, MAX_NUMBER)),
+                Ok(value) => Stored::Value(value),
+                Err(e) => Stored::Corrupt(raw, format!("{}", e))
+            }
+        }
+    }
+
+    /// Reads the value stored in exactly the given scope.
+    pub fn load(&self, server: ServerId, scope: Scope, store: &Store) -> Result<Stored, Error> {
+        store.setting_get(server.0, scope, self.0).map(|raw| self.decode(raw))
+    }
+
+    /// Reads the value stored in every scope that applies in the channel, for the user, most
+    /// specific first.  Scopes the setting can't be set in are skipped.
+    pub fn lookup(&self, server: ServerId, channel: Option<ChannelId>, user: Option<UserId>, store: &Store) -> Result<Vec<(Scope, Stored)>, Error> {
+        let scopes = Scope::resolution(channel.map(|c| c.0), user.map(|u| u.0)).into_iter()
+            .filter(|scope| self.allows(*scope)).collect::<Vec<_>>();
+        let raw: Vec<Option<String>> = store.setting_get_scopes(server.0, &scopes, self.0)?;
+        Ok(scopes.into_iter().zip(raw).map(|(scope, raw)| (scope, self.decode(raw))).collect())
+    }
+
+    /// The stored value in exactly the given scope, if there is a valid one.  A corrupted
+    /// value is logged, and treated as unset.
+    pub fn read(&self, server: ServerId, scope: Scope, store: &Store) -> Result<Option<SettingValue>, Error> {
+        let stored = self.load(server, scope, store)?;
+        Ok(self.valid(server, scope, stored))
+    }
+
+    /// The most specific valid value that applies in the channel, for the user, and the scope
+    /// it was found in.
+    pub fn resolve(&self, server: ServerId, channel: Option<ChannelId>, user: Option<UserId>, store: &Store) -> Result<Option<(Scope, SettingValue)>, Error> {
+        Ok(self.lookup(server, channel, user, store)?.into_iter()
+            .filter_map(|(scope, stored)| self.valid(server, scope, stored).map(|value| (scope, value)))
+            .next())
+    }
+
+    fn valid(&self, server: ServerId, scope: Scope, stored: Stored) -> Option<SettingValue> {
+        match stored {
+            Stored::Value(value) => Some(value),
+            Stored::Unset => None,
+            Stored::Corrupt(raw, problem) => {
+                warn!("Setting {} for server {} ({:?}) is corrupt ({}): {:?}", self.0, server, scope, problem, raw);
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, server: ServerId, scope: Scope, store: &Store) -> Result<Option<String>, Error> {
+        self.read(server, scope, store).map(|value| value.map(|v| v.to_string()))
+    }
+
+    /// Parses a value given by a user as this setting's kind.  Ids and integers larger than
+    /// `MAX_NUMBER` are refused.
+    pub fn parse(&self, value: &str) -> Option<SettingValue> {
+        let parsed = match self.1 {
             SettingKind::Channel =>
-                Ok(context.store.setting_get(server.0, self.0)?
-                    .map(|ch: String| format!("<#{}>", ch))),
+                util::parse_channel(value).and_then(|v| v.parse().ok()).map(SettingValue::Channel),
             SettingKind::User =>
-                Ok(context.store.setting_get(server.0, self.0)?
-                    .map(|user: String| format!("<@{}>", user))),
+                util::parse_mention(value).and_then(|v| v.parse().ok()).map(SettingValue::User),
             SettingKind::Role =>
-                Ok(context.store.setting_get(server.0, self.0)?
-                    .map(|role: String| format!("<@&{}>", role))),
-            SettingKind::String => context.store.setting_get(server.0, self.0),
-            SettingKind::Integer => context.store.setting_get(server.0, self.0),
-            SettingKind::Array =>
-                context.store.setting_get_array(server.0, self.0).map(|a| Some(format!("{:?}", a)))
-        }
+                util::parse_mention(value).and_then(|v| v.parse().ok()).map(SettingValue::Role),
+            SettingKind::String => Some(SettingValue::String(value.to_owned())),
+            SettingKind::Integer => value.parse().ok().map(SettingValue::Integer),
+            SettingKind::Array => Some(SettingValue::Array(vec![value.to_owned()]))
+        };
+        parsed.and_then(fitting)
     }
 
-    pub fn set(&self, server: ServerId, value: &str, context: &mut Context) -> Result<bool, Error> {
-        match self.1 {
-            SettingKind::Channel => {
-                let value: Option<u64> = util::parse_channel(value).and_then(|v| v.parse().ok());
-                if let Some(value) = value {
-                    context.store.setting_set(server.0, self.0, value)?;
-                    Ok(true)
-                } else { Ok(false) }
-            },
-            SettingKind::User | SettingKind::Role => {
-                let value: Option<u64> = util::parse_mention(value).and_then(|v| v.parse().ok());
-                if let Some(value) = value {
-                    context.store.setting_set(server.0, self.0, value)?;
-                    Ok(true)
-                } else { Ok(false) }
-            },
-            SettingKind::String => {
-                context.store.setting_set(server.0, self.0, value)?;
-                Ok(true)
-            },
-            SettingKind::Integer => {
-                let value: Option<u64> = value.parse().ok();
-                if let Some(value) = value {
-                    context.store.setting_set(server.0, self.0, value)?;
-                    Ok(true)
-                } else { Ok(false) }
-            },
-            SettingKind::Array => {
-                context.store.setting_replace_array(server.0, self.0, value)?;
-                Ok(true)
-            }
+    /// Stores a value; false if the store can't hold it.
+    fn write(&self, server: ServerId, scope: Scope, value: &SettingValue, store: &Store) -> Result<bool, Error> {
+        self.check_scope(scope)?;
+        store.setting_set(server.0, scope, self.0, &serde_json::to_string(value)?)
+    }
+
+    /// Parses and stores a value given by a user; false if it doesn't fit the setting, or
+    /// the store.
+    pub fn set(&self, server: ServerId, scope: Scope, value: &str, store: &Store) -> Result<bool, Error> {
+        match self.parse(value) {
+            Some(value) => self.write(server, scope, &value, store),
+            None => Ok(false)
         }
     }
 
-    pub fn push(&self, server: ServerId, value: &str, context: &mut Context) -> Result<bool, Error> {
-        match self.1 {
-            SettingKind::Array => context.store.setting_push_array(server.0, self.0, value).map(|_| true),
-            _ => Ok(false)
+    /// Appends an element to an array setting.  This is done by the store all at once, so that
+    /// concurrent pushes don't lose elements.
+    pub fn push(&self, server: ServerId, scope: Scope, value: &str, store: &Store) -> Result<Pushed, Error> {
+        if self.1 != SettingKind::Array { return Ok(Pushed::WrongKind); }
+        self.check_scope(scope)?;
+        Ok(match store.setting_push(server.0, scope, self.0, value)? {
+            Some(raw) => Pushed::Corrupt(raw),
+            None => Pushed::Done
+        })
+    }
+
+    pub fn clear(&self, server: ServerId, scope: Scope, store: &Store) -> Result<(), Error> {
+        self.check_scope(scope)?;
+        store.setting_clear(server.0, scope, self.0)
+    }
+
+    /// Converts a value stored before settings were typed: ids and integers as plain numbers,
+    /// strings as themselves, and arrays as lists.  Returns `None` if the value doesn't fit.
+    pub fn upgrade(&self, raw: Entry) -> Option<SettingValue> {
+        let upgraded = match (self.1, raw) {
+            (SettingKind::Array, Entry::List(values)) => Some(SettingValue::Array(values)),
+            (SettingKind::String, Entry::String(value)) => Some(SettingValue::String(value)),
+            (SettingKind::Channel, Entry::String(value)) => value.parse().ok().map(SettingValue::Channel),
+            (SettingKind::User, Entry::String(value)) => value.parse().ok().map(SettingValue::User),
+            (SettingKind::Role, Entry::String(value)) => value.parse().ok().map(SettingValue::Role),
+            (SettingKind::Integer, Entry::String(value)) => value.parse().ok().map(SettingValue::Integer),
+            _ => None
+        };
+        upgraded.and_then(fitting)
+    }
+}
+
+/// Reads a server-wide setting for a plug.  See `value_in`.
+pub fn value(name: &str, server: ServerId, context: &mut Context) -> Result<Option<SettingValue>, Error> {
+    value_in(name, server, None, None, context)
+}
+
+/// Reads a setting for a plug, as it applies in the channel, for the user: the user's value,
+/// then the channel's, then the server's.  A corrupted value is reported to the server's
+/// administration log, then cleared, so that the next scope's value is used instead.
+pub fn value_in(name: &str, server: ServerId, channel: Option<ChannelId>, user: Option<UserId>, context: &mut Context) -> Result<Option<SettingValue>, Error> {
+    let setting = find(name).ok_or_else(|| Error::ConfigurationError(format!("no setting named {}", name)))?;
+    for (scope, stored) in setting.lookup(server, channel, user, &context.store)? {
+        match stored {
+            Stored::Value(value) => return Ok(Some(value)),
+            Stored::Unset => {},
+            Stored::Corrupt(raw, problem) => reset(setting, server, scope, &raw, &problem, context)?
         }
     }
+    Ok(None)
+}
+
+/// Clears a corrupted value, and reports it to the server's administration log.
+fn reset(setting: &Setting, server: ServerId, scope: Scope, raw: &str, problem: &str, context: &mut Context) -> Result<(), Error> {
+    warn!("Setting {} for server {} ({:?}) is corrupt ({}); clearing it: {:?}", setting.0, server, scope, problem, raw);
+    setting.clear(server, scope, &context.store)?;
+    log::log(server, &format!("Setting `{}` for {} had an invalid value, and was reset.", setting.0, describe(scope)),
+        context, Some(&[("Value", raw), ("Problem", problem)][..]))
+}
 
-    pub fn clear(&self, server: ServerId, context: &mut Context) -> Result<(), Error> {
-        context.store.setting_clear(server.0, self.0)
+/// The scope, as it is shown in messages.
+pub(super) fn describe(scope: Scope) -> String {
+    match scope {
+        Scope::Server => String::from("this server"),
+        Scope::Channel(channel) => format!("<#{}>", channel),
+        Scope::User(user) => format!("<@{}>", user)
     }
 }
 
@@ -113,54 +317,242 @@ pub fn find(name: &str) -> Option<&'static Setting> {
     SETTING_TREE.get(name).map(|m| *m)
 }
 
+/// The scope named by a command argument: `server`, `here` (the channel the command was sent
+/// in), `me`, a channel in the server, or a member of it.  Only administrators may change
+/// another user's settings.
+pub(super) fn scope(argument: &str, server: ServerId, command: &Command, context: &Context) -> Result<Scope, ConfigureError> {
+    let message = &command.message;
+    let scope = match argument {
+        "server" => Scope::Server,
+        "here" => Scope::Channel(message.channel_id.0),
+        "me" => Scope::User(message.author.id.0),
+        _ => if let Some(channel) = util::parse_channel(argument).and_then(|c| c.parse().ok()) {
+            if util::server_for(ChannelId(channel), context) != Some(server) {
+                return Err(ConfigureError::Rejected(format!("<#{}> isn't a channel in this server.", channel)));
+            }
+            Scope::Channel(channel)
+        } else if let Some(user) = util::parse_mention(argument).and_then(|u| u.parse().ok()) {
+            if !util::is_member(server, UserId(user), context) {
+                return Err(ConfigureError::Rejected(format!("<@{}> isn't a member of this server.", user)));
+            }
+            Scope::User(user)
+        } else {
+            return Err(ConfigureError::Rejected(format!(
+                "`{}` isn't a scope; use `server`, `here`, `me`, a channel, or a user.", argument)));
+        }
+    };
+    Ok(scope)
+}
+
+/// The scope given at `position` in the command, or the server if none was given.
+fn scope_at(position: usize, setting: &Setting, server: ServerId, command: &Command, context: &Context) -> Result<Scope, ConfigureError> {
+    let scope = match command.arguments.get(position) {
+        Some(argument) => scope(argument, server, command, context)?,
+        None => Scope::Server
+    };
+    if !setting.allows(scope) {
+        return Err(ConfigureError::Rejected(format!("Setting `{}` can't be set for {}.", setting.0, describe(scope))));
+    }
+    Ok(scope)
+}
+
+/// Checks that the author may change a setting in the scope.
+fn check_changeable(scope: Scope, command: &Command, context: &Context) -> Result<(), ConfigureError> {
+    match scope {
+        Scope::User(user) if user != command.message.author.id.0 &&
+            !util::is_server_admin(command.message.channel_id, command.message.author.id, context) =>
+            Err(ConfigureError::Rejected("Only server administrators may change other users' settings.".into())),
+        _ => Ok(())
+    }
+}
+
+/// Records a change to a setting in the server's history, with the value it now has.
+fn changed(setting: &Setting, server: ServerId, scope: Scope, old: Option<String>, command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
+    let new = setting.get(server, scope, &context.store).map_err(|e| ConfigureError::Error(e))?;
+    history::record(server, command, setting.0, Some(scope), old, new, context);
+    Ok(())
+}
+
+/// `configure setting.get <name> [scope]`.  Without a scope, shows the value that applies
+/// to the author in this channel, and where it was set.
 pub(super) fn get(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
     let setting: &Setting = command.arguments.get(1).and_then(|name| find(name))
         .ok_or(ConfigureError::InvalidArgumentError(2))?;
     let server: ServerId = util::server_for(command.message.channel_id, context)
         .ok_or(ConfigureError::NonPublicError)?;
-    let value = setting.get(server, context).map_err(|e| ConfigureError::Error(e))?;
+    let found = if command.arguments.get(2).is_some() {
+        let scope = scope_at(2, setting, server, command, context)?;
+        setting.read(server, scope, &context.store).map_err(|e| ConfigureError::Error(e))?.map(|value| (scope, value))
+    } else {
+        setting.resolve(server, Some(command.message.channel_id), Some(command.message.author.id), &context.store)
+            .map_err(|e| ConfigureError::Error(e))?
+    };
 
-    match value {
-        Some(value) => util::send_info_embed(&format!("Setting `{}` is set to `{}`.", setting.0, value),
+    match found {
+        Some((scope, value)) => util::send_info_embed(
+            &format!("Setting `{}` is set to `{}` for {}.", setting.0, value, describe(scope)),
             command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e)),
         None => util::send_info_embed(&format!("Setting `{}` is not set.", setting.0),
             command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e))
     }
 }
 
+/// `configure setting.set <name> <value> [scope]`.
 pub(super) fn set(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
     let setting: &Setting = command.arguments.get(1).and_then(|name| find(name))
         .ok_or(ConfigureError::InvalidArgumentError(2))?;
     let server: ServerId = util::server_for(command.message.channel_id, context)
         .ok_or(ConfigureError::NonPublicError)?;
     let value = command.arguments.get(2).ok_or(ConfigureError::InvalidArgumentError(3))?;
-    let success = setting.set(server, value, context).map_err(|e| ConfigureError::Error(e))?;
+    let scope = scope_at(3, setting, server, command, context)?;
+    check_changeable(scope, command, context)?;
+    let old = setting.get(server, scope, &context.store).map_err(|e| ConfigureError::Error(e))?;
+    let success = setting.set(server, scope, value, &context.store).map_err(|e| ConfigureError::Error(e))?;
 
     if !success { return Err(ConfigureError::FormatError); }
-    util::send_success_embed(&format!("Setting `{}` was set to `{}`.", setting.0, value),
+    changed(setting, server, scope, old, command, context)?;
+    util::send_success_embed(&format!("Setting `{}` was set to `{}` for {}.", setting.0, value, describe(scope)),
         command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e))
 }
 
+/// `configure setting.push <name> <value> [scope]`.
 pub(super) fn push(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
     let setting: &Setting = command.arguments.get(1).and_then(|name| find(name))
         .ok_or(ConfigureError::InvalidArgumentError(2))?;
     let server: ServerId = util::server_for(command.message.channel_id, context)
         .ok_or(ConfigureError::NonPublicError)?;
     let value = command.arguments.get(2).ok_or(ConfigureError::InvalidArgumentError(3))?;
-    let success = setting.push(server, value, context).map_err(|e| ConfigureError::Error(e))?;
+    let scope = scope_at(3, setting, server, command, context)?;
+    check_changeable(scope, command, context)?;
+    let old = setting.get(server, scope, &context.store).map_err(|e| ConfigureError::Error(e))?;
+    // A corrupted value is reset, as it would be when read by a plug, and the push tried again.
+    let pushed = match setting.push(server, scope, value, &context.store).map_err(|e| ConfigureError::Error(e))? {
+        Pushed::Corrupt(raw) => {
+            reset(setting, server, scope, &raw, "not an array", context).map_err(|e| ConfigureError::Error(e))?;
+            setting.push(server, scope, value, &context.store).map_err(|e| ConfigureError::Error(e))?
+        },
+        pushed => pushed
+    };
 
-    if !success { return Err(ConfigureError::FormatError); }
-    util::send_success_embed(&format!("Setting `{}` now has element `{}`.", setting.0, value),
+    match pushed {
+        Pushed::Done => {},
+        Pushed::WrongKind => return Err(ConfigureError::FormatError),
+        Pushed::Corrupt(_) => return Err(ConfigureError::Rejected(
+            format!("Setting `{}` has an invalid value that couldn't be reset.", setting.0)))
+    }
+    changed(setting, server, scope, old, command, context)?;
+    util::send_success_embed(&format!("Setting `{}` now has element `{}` for {}.", setting.0, value, describe(scope)),
         command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e))
 }
 
+/// `configure setting.clear <name> [scope]`.
 pub(super) fn clear(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
     let setting: &Setting = command.arguments.get(1).and_then(|name| find(name))
         .ok_or(ConfigureError::InvalidArgumentError(2))?;
     let server: ServerId = util::server_for(command.message.channel_id, context)
         .ok_or(ConfigureError::NonPublicError)?;
-    setting.clear(server, context).map_err(|e| ConfigureError::Error(e))?;
+    let scope = scope_at(2, setting, server, command, context)?;
+    check_changeable(scope, command, context)?;
+    let old = setting.get(server, scope, &context.store).map_err(|e| ConfigureError::Error(e))?;
+    setting.clear(server, scope, &context.store).map_err(|e| ConfigureError::Error(e))?;
+    changed(setting, server, scope, old, command, context)?;
 
-    util::send_success_embed(&format!("Setting `{}` was cleared.", setting.0),
+    util::send_success_embed(&format!("Setting `{}` was cleared for {}.", setting.0, describe(scope)),
         command.message.channel_id, context).map(|_| ()).map_err(|e| ConfigureError::Error(e))
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use store::{Entry, ScopeKind};
+    use super::{Setting, SettingKind, SettingValue, Stored, ANY};
+
+    fn setting(kind: SettingKind) -> Setting { Setting("test", kind, ANY) }
+
+    fn corrupt(stored: Stored) -> bool {
+        match stored { Stored::Corrupt(_, _) => true, _ => false }
+    }
+
+    #[test]
+    fn values_serialize_with_their_kind() {
+        assert_eq!(serde_json::to_string(&SettingValue::Channel(12)).unwrap(), r#"{"type":"channel","value":12}"#);
+        assert_eq!(serde_json::to_string(&SettingValue::Array(vec!["a".into()])).unwrap(),
+            r#"{"type":"array","value":["a"]}"#);
+    }
+
+    #[test]
+    fn decode_reads_values_of_the_setting_kind() {
+        assert_eq!(setting(SettingKind::Channel).decode(None), Stored::Unset);
+        assert_eq!(setting(SettingKind::Channel).decode(Some(r#"{"type":"channel","value":12}"#.into())),
+            Stored::Value(SettingValue::Channel(12)));
+        assert_eq!(setting(SettingKind::Array).decode(Some(r#"{"type":"array","value":["a","b"]}"#.into())),
+            Stored::Value(SettingValue::Array(vec!["a".into(), "b".into()])));
+    }
+
+    #[test]
+    fn decode_rejects_other_kinds_and_untyped_values() {
+        // A role id stored for a channel setting is the same number, but not the same thing.
+        assert!(corrupt(setting(SettingKind::Channel).decode(Some(r#"{"type":"role","value":12}"#.into()))));
+        // Values stored before settings were typed.
+        assert!(corrupt(setting(SettingKind::Channel).decode(Some("12".into()))));
+        assert!(corrupt(setting(SettingKind::String).decode(Some("hello".into()))));
+        assert!(corrupt(setting(SettingKind::Integer).decode(Some(r#"{"type":"integer","value":-1}"#.into()))));
+        match setting(SettingKind::Channel).decode(Some(r#"{"type":"role","value":12}"#.into())) {
+            Stored::Corrupt(raw, problem) => {
+                assert_eq!(raw, r#"{"type":"role","value":12}"#);
+                assert!(problem.contains("Role"));
+            },
+            stored => panic!("expected a corrupt value, but found {:?}", stored)
+        }
+    }
+
+    #[test]
+    fn parse_reads_mentions_for_ids() {
+        assert_eq!(setting(SettingKind::Channel).parse("<#12>"), Some(SettingValue::Channel(12)));
+        assert_eq!(setting(SettingKind::Channel).parse("12"), None);
+        assert_eq!(setting(SettingKind::User).parse("<@12>"), Some(SettingValue::User(12)));
+        assert_eq!(setting(SettingKind::User).parse("<@!12>"), Some(SettingValue::User(12)));
+        assert_eq!(setting(SettingKind::Role).parse("<@&12>"), Some(SettingValue::Role(12)));
+        assert_eq!(setting(SettingKind::Role).parse("everyone"), None);
+    }
+
+    #[test]
+    fn parse_reads_plain_values() {
+        assert_eq!(setting(SettingKind::Integer).parse("42"), Some(SettingValue::Integer(42)));
+        assert_eq!(setting(SettingKind::Integer).parse("-1"), None);
+        assert_eq!(setting(SettingKind::Integer).parse("4.2"), None);
+        assert_eq!(setting(SettingKind::String).parse("<#12>"), Some(SettingValue::String("<#12>".into())));
+        assert_eq!(setting(SettingKind::Array).parse("a b"), Some(SettingValue::Array(vec!["a b".into()])));
+    }
+
+    #[test]
+    fn numbers_past_i64_are_refused() {
+        assert_eq!(setting(SettingKind::Integer).parse("9223372036854775807"), Some(SettingValue::Integer(9223372036854775807)));
+        assert_eq!(setting(SettingKind::Integer).parse("9223372036854775808"), None);
+        assert_eq!(setting(SettingKind::Channel).parse("<#18446744073709551615>"), None);
+        assert_eq!(setting(SettingKind::Integer).upgrade(Entry::String("9223372036854775808".into())), None);
+        assert!(corrupt(setting(SettingKind::Integer).decode(Some(r#"{"type":"integer","value":9223372036854775808}"#.into()))));
+    }
+
+    #[test]
+    fn upgrade_converts_untyped_values() {
+        assert_eq!(setting(SettingKind::Channel).upgrade(Entry::String("12".into())), Some(SettingValue::Channel(12)));
+        assert_eq!(setting(SettingKind::Integer).upgrade(Entry::String("7".into())), Some(SettingValue::Integer(7)));
+        assert_eq!(setting(SettingKind::String).upgrade(Entry::String("hi".into())), Some(SettingValue::String("hi".into())));
+        assert_eq!(setting(SettingKind::Array).upgrade(Entry::List(vec!["a".into(), "b".into()])),
+            Some(SettingValue::Array(vec!["a".into(), "b".into()])));
+    }
+
+    #[test]
+    fn upgrade_refuses_values_that_do_not_fit() {
+        assert_eq!(setting(SettingKind::Channel).upgrade(Entry::String("general".into())), None);
+        assert_eq!(setting(SettingKind::Array).upgrade(Entry::String("a".into())), None);
+        assert_eq!(setting(SettingKind::String).upgrade(Entry::List(vec!["a".into()])), None);
+        assert_eq!(setting(SettingKind::Integer).upgrade(Entry::Set(vec!["1".into()])), None);
+    }
+
+    #[test]
+    fn every_setting_may_be_set_for_the_server() {
+        for setting in super::SETTINGS { assert!(setting.scopes().contains(&ScopeKind::Server), "{}", setting.name()); }
+    }
+}
@@ -0,0 +1,85 @@
+use shard::Context;
+use shard::plug::Command;
+use shard::util;
+use store::{Export, ImportMode};
+use discord::model::ServerId;
+use super::ConfigureError;
+
+/// Longer exports are sent as a file, since they wouldn't fit in a message.
+static MAX_INLINE: usize = 1900;
+/// The most changes listed after an import.
+static MAX_LISTED: usize = 20;
+
+fn admin_server(command: &Command, context: &Context) -> Result<ServerId, ConfigureError> {
+    let server: ServerId = util::server_for(command.message.channel_id, context)
+        .ok_or(ConfigureError::NonPublicError)?;
+    if !util::is_server_admin(command.message.channel_id, command.message.author.id, context) {
+        return Err(ConfigureError::Rejected("Only server administrators may export or import the configuration.".into()));
+    }
+    Ok(server)
+}
+
+/// The contents of the first code block in a message, without any language tag.
+fn code_block(content: &str) -> Option<&str> {
+    let start = content.find("```")? + 3;
+    let end = content[start..].find("```")? + start;
+    let block = &content[start..end];
+    let body = match block.find('\n') {
+        Some(newline) if !block[..newline].trim().contains(char::is_whitespace) &&
+            !block[..newline].trim().starts_with('{') => &block[newline + 1..],
+        _ => block
+    };
+    Some(body.trim())
+}
+
+pub(super) fn export(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
+    let server = admin_server(command, context)?;
+    let export = context.store.server_export(server.0).map_err(|e| ConfigureError::Error(e))?.to_json();
+    let channel = command.message.channel_id;
+
+    if export.len() <= MAX_INLINE {
+        util::send(&format!("```json\n{}\n```", export), channel, context)
+    } else {
+        util::send_file("", export.as_bytes(), &format!("{}.json", server.0), channel, context)
+    }.map(|_| ()).map_err(|e| ConfigureError::Error(e))
+}
+
+/// `configure import [replace] [dry-run]`, followed by an export in a code block.
+pub(super) fn import(command: &Command, context: &mut Context) -> Result<(), ConfigureError> {
+    let server = admin_server(command, context)?;
+    let json = code_block(&command.message.content).ok_or(ConfigureError::Rejected(
+        "Include the export to import in a code block.".into()))?;
+    let export = Export::from_json(json).map_err(|e| ConfigureError::Rejected(format!("{}", e)))?;
+    let flags = command.arguments.iter().skip(1).take_while(|a| !a.starts_with("```")).collect::<Vec<_>>();
+    let mode = if flags.iter().any(|f| **f == "replace") { ImportMode::Replace } else { ImportMode::Merge };
+    let dry_run = flags.iter().any(|f| **f == "dry-run");
+
+    let rejected = export.rejected();
+    let mut refused = rejected.iter().take(MAX_LISTED).map(|r| format!("`{}`", r)).collect::<Vec<_>>();
+    if rejected.len() > MAX_LISTED { refused.push(format!("...and {} more.", rejected.len() - MAX_LISTED)); }
+    if !rejected.is_empty() && !dry_run {
+        return Err(ConfigureError::Rejected(format!("Nothing was imported; {} key(s) can't be:\n{}",
+            rejected.len(), refused.join("\n"))));
+    }
+
+    let changes = if dry_run {
+        let current = context.store.server_export(server.0).map_err(|e| ConfigureError::Error(e))?;
+        export.diff(&current, mode)
+    } else {
+        context.store.server_import(server.0, &export, mode).map_err(|e| ConfigureError::Error(e))?
+    };
+
+    let mut listed = changes.iter().take(MAX_LISTED).map(|c| format!("`{}`", c)).collect::<Vec<_>>();
+    if changes.len() > MAX_LISTED { listed.push(format!("...and {} more.", changes.len() - MAX_LISTED)); }
+    let summary = match (changes.len(), dry_run) {
+        (0, true) => String::from("Nothing would change."),
+        (0, false) => String::from("Nothing changed."),
+        (n, true) => format!("{} change(s) would be made:\n{}", n, listed.join("\n")),
+        (n, false) => format!("{} change(s) were made:\n{}", n, listed.join("\n"))
+    };
+    let summary = if rejected.is_empty() { summary } else {
+        format!("{}\n{} key(s) can't be imported, so the import would be refused:\n{}", summary, rejected.len(), refused.join("\n"))
+    };
+    util::send_success_embed(&summary, command.message.channel_id, context)
+        .map(|_| ()).map_err(|e| ConfigureError::Error(e))
+}
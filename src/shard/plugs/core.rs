@@ -19,6 +19,7 @@ plug! { Ping => {
     }
 }, Missing => {
     fn matches_name(&self, _: &str) -> bool { true }
+    fn is_fallback(&self) -> bool { true }
     fn handle_command(&self, command: &Command, context: &mut Context) -> PlugResult {
         info!("Command `{}' was attempted, but didn't match.", command.name);
         util::send_error_embed(&format!("Unknown command `{}`", command.name),
@@ -1,18 +1,42 @@
 mod administration;
 mod comfort;
-mod configuration;
+pub(crate) mod configuration;
 mod core;
+mod stats;
 
-use shard::plug::PlugSet;
+use std::collections::BTreeMap;
+use toml;
+use configuration::{Configuration, Diagnostic};
+use shard::plug::{PlugConfigDefaults, PlugConfigLoader, PlugConfigs, PlugConfigVisitor, PlugSet};
 
 pub fn init() -> PlugSet {
     let mut set = PlugSet::new();
     comfort::init(&mut set);
     configuration::init(&mut set);
     administration::init(&mut set);
+    stats::init(&mut set);
     // utility::init(&mut set);
 
     // core *must* come last.
     core::init(&mut set);
     set
 }
+
+/// Calls the visitor with every plug's configuration type.
+fn configs<V: PlugConfigVisitor>(visitor: &mut V) {
+    visitor.visit::<comfort::ComfortConfig>();
+}
+
+/// Parses and validates every plug's configuration.
+pub fn load_configs(configuration: &Configuration) -> Result<PlugConfigs, Vec<Diagnostic>> {
+    let mut loader = PlugConfigLoader { configuration, configs: PlugConfigs::default(), problems: vec![] };
+    configs(&mut loader);
+    if loader.problems.is_empty() { Ok(loader.configs) } else { Err(loader.problems) }
+}
+
+/// The default `[plugs.<name>]` tables, for writing a new configuration file.
+pub fn default_configs() -> BTreeMap<String, toml::Value> {
+    let mut defaults = PlugConfigDefaults::default();
+    configs(&mut defaults);
+    defaults.0
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use shard::Context;
+use shard::plug::{Command, Plug, PlugSet, PlugStatus, PlugResult};
+use shard::util;
+use discord::model::ChannelId;
+use error::Error;
+
+static SHOWN_COMMANDS: usize = 10;
+
+#[derive(Debug, Clone, Default)]
+struct Usage { name: String, ok: u64, err: u64, ms: u64 }
+
+impl Usage {
+    fn total(&self) -> u64 { self.ok + self.err }
+
+    fn error_rate(&self) -> f64 {
+        if self.total() == 0 { 0.0 } else { self.err as f64 * 100.0 / self.total() as f64 }
+    }
+
+    fn describe(&self) -> String {
+        format!("{} uses, {:.1}% errors, {}ms average", self.total(), self.error_rate(),
+            if self.total() == 0 { 0 } else { self.ms / self.total() })
+    }
+}
+
+fn period(argument: Option<&&str>) -> Option<(&'static str, u64)> {
+    match argument.map(|s| *s) {
+        None | Some("24h") => Some(("24 hours", 24)),
+        Some("7d") => Some(("7 days", 24 * 7)),
+        Some("30d") => Some(("30 days", 24 * 30)),
+        _ => None
+    }
+}
+
+fn summarize(totals: HashMap<String, u64>) -> Vec<Usage> {
+    let mut usages: HashMap<String, Usage> = HashMap::new();
+    for (field, count) in totals {
+        let split = match field.rfind(':') { Some(split) => split, None => continue };
+        let (name, kind) = (&field[..split], &field[(split + 1)..]);
+        let usage = usages.entry(name.to_owned())
+            .or_insert_with(|| Usage { name: name.to_owned(), ..Usage::default() });
+        match kind {
+            "ok" => usage.ok += count,
+            "err" => usage.err += count,
+            "ms" => usage.ms += count,
+            _ => {}
+        }
+    }
+
+    let mut usages = usages.into_iter().map(|(_, usage)| usage).collect::<Vec<_>>();
+    usages.sort_by(|a, b| b.total().cmp(&a.total()).then_with(|| a.name.cmp(&b.name)));
+    usages
+}
+
+fn send_usage(title: &str, usages: &[Usage], channel: ChannelId, context: &Context) -> Result<(), Error> {
+    if usages.is_empty() {
+        return util::send_info_embed(&format!("{}: no commands were used.", title), channel, context).map(|_| ());
+    }
+
+    let overall = usages.iter().fold(Usage::default(), |sum, usage|
+        Usage { ok: sum.ok + usage.ok, err: sum.err + usage.err, ms: sum.ms + usage.ms, ..sum });
+    util::send_embed(channel, context, |e| {
+        e.title(title).description(&overall.describe()).color(util::INFO_COLOR)
+            .fields(|mut f| {
+                for usage in usages.iter().take(SHOWN_COMMANDS) { f = f.field(&usage.name, &usage.describe(), false) }
+                f
+            }).author(|a| util::build_embed_author(a, context))
+    }).map(|_| ())
+}
+
+plug! { Stats => {
+    fn matches_name(&self, name: &str) -> bool { name == "stats" }
+    fn handle_command(&self, command: &Command, context: &mut Context) -> PlugResult {
+        let channel = command.message.channel_id;
+        let global = command.arguments.get(0) == Some(&"global");
+        let position = if global { 1 } else { 0 };
+        let (label, hours) = match period(command.arguments.get(position)) {
+            Some(period) => period,
+            None => {
+                util::send_incorrect_argument(position + 1, channel, context)?;
+                return Ok(PlugStatus::Failed);
+            }
+        };
+
+        let (server, title) = if global {
+            if !util::is_owner(command.message.author.id, context) {
+                util::send_error_embed("Only the bot's owners may view global statistics.", channel, context)?;
+                return Ok(PlugStatus::Failed);
+            }
+            (None, format!("Global command usage over the last {}", label))
+        } else {
+            let server = match util::server_for(channel, context) {
+                Some(server) => server,
+                None => {
+                    util::send_must_public(channel, context)?;
+                    return Ok(PlugStatus::Failed);
+                }
+            };
+            if !util::is_server_admin(channel, command.message.author.id, context) {
+                util::send_error_embed("Only server administrators may view statistics.", channel, context)?;
+                return Ok(PlugStatus::Failed);
+            }
+            (Some(server.0), format!("Command usage over the last {}", label))
+        };
+
+        let usages = summarize(context.store.stats_read(server, hours)?);
+        send_usage(&title, &usages, channel, context)?;
+        Ok(PlugStatus::Stop)
+    }
+} }
+
+pub(super) fn init(set: &mut PlugSet) {
+    set.push(Stats);
+}
@@ -0,0 +1,74 @@
+// Builds gateway payloads that never came from discord, for running plugs locally (see
+// `shell`) and for the results of actions that weren't sent.  The payloads are decoded by the
+// library itself, so they have the same shape as the real thing.
+
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use discord::model::{LiveServer, Message, ReadyEvent};
+use serde_json::{self, Value};
+use super::Error;
+
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+static TIMESTAMP: &'static str = "2017-01-01T00:00:00.000000+00:00";
+
+/// A fresh id, for messages and the like.  These are small, so they can't clash with any real
+/// snowflake.
+pub fn id() -> u64 { 1_000_000 + NEXT_ID.fetch_add(1, Ordering::SeqCst) as u64 }
+
+fn user(id: u64, name: &str, bot: bool) -> Value {
+    json!({
+        "id": id.to_string(), "username": name, "discriminator": "0000", "avatar": null, "bot": bot
+    })
+}
+
+/// The ready event for a bot user that is in a single server.
+pub fn ready(bot: u64, name: &str, server: u64) -> Result<ReadyEvent, Error> {
+    Ok(serde_json::from_value(json!({
+        "v": 6,
+        "user": {
+            "id": bot.to_string(), "username": name, "discriminator": "0000", "avatar": null,
+            "bot": true, "verified": true, "email": null, "mfa_enabled": false
+        },
+        "session_id": "synthetic",
+        "guilds": [{ "id": server.to_string(), "unavailable": true }],
+        "private_channels": [],
+        "presences": [],
+        "relationships": [],
+        "shard": [0, 1],
+        "_trace": []
+    }))?)
+}
+
+/// A server with a single text channel, owned by `owner`, with the bot and the owner as its
+/// members.  Since the owner has every permission, they count as a server administrator.
+pub fn server(server: u64, channel: u64, owner: u64, bot: u64, name: &str) -> Result<LiveServer, Error> {
+    let member = |user: Value| json!({
+        "user": user, "roles": [], "nick": null, "joined_at": TIMESTAMP, "deaf": false, "mute": false
+    });
+    Ok(serde_json::from_value(json!({
+        "id": server.to_string(), "name": "synthetic", "owner_id": owner.to_string(), "icon": null,
+        "region": "local", "afk_channel_id": null, "afk_timeout": 300, "verification_level": 0,
+        "default_message_notifications": 0, "explicit_content_filter": 0, "mfa_level": 0,
+        "features": [], "emojis": [], "presences": [], "voice_states": [],
+        "roles": [{
+            "id": server.to_string(), "name": "@everyone", "color": 0, "hoist": false,
+            "managed": false, "position": 0, "mentionable": false, "permissions": 104324161
+        }],
+        "members": [member(user(owner, "shell", false)), member(user(bot, name, true))],
+        "channels": [{
+            "id": channel.to_string(), "name": "shell", "type": 0, "position": 0,
+            "permission_overwrites": [], "topic": null, "last_message_id": null, "nsfw": false
+        }],
+        "large": false, "member_count": 2, "joined_at": TIMESTAMP
+    }))?)
+}
+
+/// A message sent to a channel.
+pub fn message(channel: u64, author: u64, name: &str, bot: bool, content: &str, embeds: Vec<Value>) -> Result<Message, Error> {
+    Ok(serde_json::from_value(json!({
+        "id": id().to_string(), "channel_id": channel.to_string(), "content": content,
+        "nonce": null, "tts": false, "timestamp": TIMESTAMP, "edited_timestamp": null,
+        "pinned": false, "type": 0, "author": user(author, name, bot),
+        "mention_everyone": false, "mentions": [], "mention_roles": [], "reactions": [],
+        "attachments": [], "embeds": embeds
+    }))?)
+}
@@ -1,9 +1,12 @@
 use discord::builders::{EmbedBuilder, EmbedAuthorBuilder};
-use discord::model::{Message, ChannelId, ServerId};
+use discord::model::{Event, Message, ChannelId, ServerId, UserId};
+use discord::model::permissions;
 use discord::Error as DiscordError;
 use discord::ChannelRef;
 use hyper::status::StatusCode;
-use super::{Context, Error};
+use serde_json::Value;
+use super::{Context, Error, Outbound};
+use super::synthetic;
 use rand;
 use rand::Rng;
 use regex::Regex;
@@ -13,14 +16,92 @@ pub const ERROR_COLOR: u64 = 0xff4013;
 pub const SUCCESS_COLOR: u64 = 0x13ff40;
 pub const INFO_COLOR: u64 = 0x13d2ff;
 
+/// The longest chain of aliases that will be followed before giving up.  Cycles are rejected
+/// when an alias is created, so this only guards against data edited by hand.
+pub const MAX_ALIAS_DEPTH: usize = 8;
+
 pub fn server_for(channel: ChannelId, context: &Context) -> Option<ServerId> {
     if let Some(ChannelRef::Public(server, _)) = context.state.find_channel(channel) {
         Some(server.id)
     } else { None }
 }
 
+/// Whether the user may administrate the server that the channel belongs to, i.e. they have
+/// the administrator or manage server permission there.
+pub fn is_server_admin(channel: ChannelId, user: UserId, context: &Context) -> bool {
+    match context.state.find_channel(channel) {
+        Some(ChannelRef::Public(server, _)) => {
+            let granted = server.permissions_for(channel, user);
+            granted.contains(permissions::ADMINISTRATOR) || granted.contains(permissions::MANAGE_SERVER)
+        },
+        _ => false
+    }
+}
+
+/// Whether the user is a member of the server, as far as the shard's state knows.
+pub fn is_member(server: ServerId, user: UserId, context: &Context) -> bool {
+    context.state.servers().iter().find(|s| s.id == server)
+        .map(|s| s.members.iter().any(|m| m.user.id == user))
+        .unwrap_or(false)
+}
+
+pub fn is_owner(user: UserId, context: &Context) -> bool {
+    context.configuration.owners.contains(&(user.0 as i64))
+}
+
+/// Follows the server's aliases starting at `name`, returning every name visited in order
+/// (including `name` itself).  The chain ends at the first name that isn't an alias, or just
+/// before a name would repeat.
+pub fn alias_chain(server: ServerId, name: &str, context: &Context) -> Result<Vec<String>, Error> {
+    let mut chain = vec![name.to_owned()];
+    while chain.len() <= MAX_ALIAS_DEPTH {
+        match context.store.alias_get(server.0, chain.last().unwrap())? {
+            Some(ref next) if chain.contains(next) => break,
+            Some(next) => chain.push(next),
+            None => break
+        }
+    }
+    Ok(chain)
+}
+
+pub fn resolve_alias(server: ServerId, name: &str, context: &Context) -> Result<String, Error> {
+    alias_chain(server, name, context).map(|mut chain| chain.pop().unwrap())
+}
+
+/// The server an event happened in, for the events where that is known.
+pub fn event_server(event: &Event, context: &Context) -> Option<ServerId> {
+    match event {
+        &Event::MessageCreate(ref message) => server_for(message.channel_id, context),
+        &Event::ServerMemberAdd(server, _) => Some(server),
+        _ => None
+    }
+}
+
 pub fn send(message: &str, channel: ChannelId, context: &Context) -> Result<Option<Message>, Error> {
-    allow_forbidden(context.discord.send_message(channel, message, &generate_nonce(), false))
+    match context.outbound {
+        Outbound::Discord => allow_forbidden(context.discord.send_message(channel, message, &generate_nonce(), false)),
+        Outbound::Terminal => { ::shell::print(message, None); sent(channel, message, vec![], context) },
+        Outbound::DryRun => {
+            warn!(target: "dry_run", "send_message to {}: {:?}", channel, message);
+            sent(channel, message, vec![], context)
+        }
+    }
+}
+
+pub fn send_file(message: &str, file: &[u8], filename: &str, channel: ChannelId, context: &Context) -> Result<Option<Message>, Error> {
+    match context.outbound {
+        Outbound::Discord => allow_forbidden(context.discord.send_file(channel, message, file, filename)),
+        Outbound::Terminal => {
+            ::shell::print(&format!("{}\n[{}, {} bytes]\n{}", message, filename, file.len(),
+                String::from_utf8_lossy(file)), None);
+            sent(channel, message, vec![], context)
+        },
+        Outbound::DryRun => {
+            warn!(target: "dry_run", "send_file to {}: {:?}, {} ({} bytes): {}", channel, message, filename,
+                file.len(), String::from_utf8_lossy(file));
+            sent(channel, message, vec![], context)
+        }
+    }
 }
 
 pub fn send_incorrect_argument(position: usize, channel: ChannelId, context: &Context) -> Result<Option<Message>, Error> {
@@ -38,7 +119,25 @@ pub fn send_embed<F: FnOnce(EmbedBuilder) -> EmbedBuilder>(
     channel: ChannelId,
     context: &Context,
     f: F) -> Result<Option<Message>, Error> {
-    allow_forbidden(context.discord.send_embed(channel, "", f))
+    match context.outbound {
+        Outbound::Discord => allow_forbidden(context.discord.send_embed(channel, "", f)),
+        Outbound::Terminal => {
+            let embed = Value::Object(EmbedBuilder::__build(f));
+            ::shell::print("", Some(&embed));
+            sent(channel, "", vec![embed], context)
+        },
+        Outbound::DryRun => {
+            let embed = Value::Object(EmbedBuilder::__build(f));
+            warn!(target: "dry_run", "send_embed to {}: {}", channel, embed);
+            sent(channel, "", vec![embed], context)
+        }
+    }
+}
+
+/// The message discord would have returned, had it been sent by the bot.
+fn sent(channel: ChannelId, content: &str, embeds: Vec<Value>, context: &Context) -> Result<Option<Message>, Error> {
+    let user = context.state.user();
+    synthetic::message(channel.0, user.id.0, &user.username, true, content, embeds).map(Some)
 }
 
 pub fn send_error_embed(message: &str, channel: ChannelId, context: &Context) -> Result<Option<Message>, Error> {
@@ -70,7 +169,7 @@ pub fn build_embed_author(author: EmbedAuthorBuilder, context: &Context) -> Embe
         } else {
             format!("https://cdn.discordapp.com/embed/avatars/{}.png", user.id.0 % 5)
         };
-    author.name(&context.shard.configuration.name).icon_url(&icon)
+    author.name(&context.configuration.name).icon_url(&icon)
 }
 
 pub fn parse_channel(value: &str) -> Option<&str> {
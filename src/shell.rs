@@ -0,0 +1,79 @@
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+use clap::{App as Application, Arg as Argument, ArgMatches, SubCommand};
+use discord::State;
+use discord::model::{Event, PossibleServer};
+use serde_json::Value;
+use configuration::{Configuration, Reloadable};
+use error::Error;
+use shard::{self, Outbound, Shard};
+use shard::synthetic;
+
+/// The id of the bot user in a shell session.
+static BOT: u64 = 4;
+
+pub fn subcommand<'a>() -> Application<'a, 'a> {
+    let id = |name, default, help| Argument::with_name(name).long(name).value_name("ID")
+        .takes_value(true).default_value(default).help(help);
+    SubCommand::with_name("shell").about("runs typed messages through the plugs, printing the replies")
+        .arg(id("server", "1", "the id of the fake server"))
+        .arg(id("channel", "2", "the id of the fake channel"))
+        .arg(id("user", "3", "the id of the fake user, who owns the fake server"))
+}
+
+/// Runs an interactive session: every line typed is sent as a message from a fake user, in a
+/// fake server, through the real plugs and store.  Anything the plugs send is printed rather
+/// than sent to discord.
+pub fn run(matches: &ArgMatches, config: &Configuration) -> Result<i32, Error> {
+    let server = matches.value_of("server").unwrap_or("1").parse()?;
+    let channel = matches.value_of("channel").unwrap_or("2").parse()?;
+    let user = matches.value_of("user").unwrap_or("3").parse()?;
+
+    let configs = shard::plugs::load_configs(config).map_err(|problems| Error::ConfigurationError(
+        problems.iter().map(|p| p.to_string()).collect::<Vec<_>>().join("; ")))?;
+    let reloadable = Arc::new(Reloadable::new(config.clone(), &[]));
+    let shard = Shard::new(0, reloadable, shard::init(), configs);
+    let state = State::new(synthetic::ready(BOT, &config.name, server)?);
+    let mut context = shard.offline_context(state, Outbound::Terminal)?;
+    let live = synthetic::server(server, channel, user, BOT, &config.name)?;
+    context.state.update(&Event::ServerCreate(PossibleServer::Online(live)));
+    context.start()?;
+
+    println!("Messages are sent by user {} in channel {} of server {}; the prefix is `{}`.",
+        user, channel, server, context.configuration.prefix);
+    println!("Type `:quit` or end the input to leave.");
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 { println!(); break; }
+        let line = line.trim();
+        if line.is_empty() { continue; }
+        if line == ":quit" { break; }
+
+        let message = synthetic::message(channel, user, "shell", false, line, vec![])?;
+        if let Err(e) = context.dispatch(&Event::MessageCreate(message)) {
+            println!("error: {}", e);
+        }
+    }
+    Ok(0)
+}
+
+/// Prints a message the bot would have sent.
+pub fn print(content: &str, embed: Option<&Value>) {
+    for line in content.lines() { println!("< {}", line); }
+    let embed = match embed { Some(embed) => embed, None => return };
+    let text = |value: &Value, key: &str| value.get(key).and_then(|v| v.as_str()).map(|s| s.to_owned());
+
+    if let Some(author) = embed.get("author").and_then(|a| text(a, "name")) { println!("< | {}", author); }
+    if let Some(title) = text(embed, "title") { println!("< | **{}**", title); }
+    if let Some(description) = text(embed, "description") {
+        for line in description.lines() { println!("< | {}", line); }
+    }
+    for field in embed.get("fields").and_then(|f| f.as_array()).map(|f| &f[..]).unwrap_or(&[]) {
+        println!("< | {}:", text(field, "name").unwrap_or_default());
+        for line in text(field, "value").unwrap_or_default().lines() { println!("< |   {}", line); }
+    }
+    if let Some(footer) = embed.get("footer").and_then(|f| text(f, "text")) { println!("< | -- {}", footer); }
+}
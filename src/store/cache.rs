@@ -0,0 +1,176 @@
+// An in-process cache of module flags and settings, shared by every store in the process.
+// Writes publish the keys they changed, and every process drops those keys from its cache when
+// it hears of them.  An invalidation can still be missed, e.g. while the subscription is being
+// reconnected, so entries also expire after `bot.cache_ttl` seconds, and nothing is read from
+// the cache while unsubscribed.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::{Duration, Instant};
+use redis::{self, Client, Commands, Value};
+use configuration::Bot;
+use metrics;
+use super::{Backend, Error, Store};
+
+/// The channel invalidations are published on, within the configured prefix.  Each message is
+/// a key prefix; every cached key starting with it is dropped.
+static CHANNEL: &'static str = "cache:invalidate";
+
+/// Whether this process is subscribed to invalidations.
+static LISTENING: AtomicBool = ATOMIC_BOOL_INIT;
+/// Bumped on every invalidation, so that a value read before an invalidation isn't cached
+/// after it.
+static GENERATION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The most keys cached at once, counting unset ones.  When a read would go past it, expired
+/// entries are dropped, and if that isn't enough, the whole cache is.
+const MAX_ENTRIES: usize = 100_000;
+
+lazy_static! {
+    static ref ENTRIES: Mutex<HashMap<String, (Instant, Value)>> = Mutex::new(HashMap::new());
+}
+
+fn with_entries<T, F: FnOnce(&mut HashMap<String, (Instant, Value)>) -> T>(f: F) -> T {
+    match ENTRIES.lock() {
+        Ok(mut entries) => f(&mut entries),
+        Err(poisoned) => f(&mut poisoned.into_inner())
+    }
+}
+
+/// Makes room for `adding` more entries, keeping the cache under `MAX_ENTRIES`.
+fn make_room(entries: &mut HashMap<String, (Instant, Value)>, adding: usize, now: Instant, ttl: Duration) {
+    if entries.len() + adding <= MAX_ENTRIES { return; }
+    entries.retain(|_, &mut (stored, _)| now.duration_since(stored) < ttl);
+    if entries.len() + adding > MAX_ENTRIES {
+        debug!("The cache is full of live entries; dropping all {} of them.", entries.len());
+        entries.clear();
+    }
+}
+
+/// Drops every key starting with `prefix` from this process's cache.
+fn forget(prefix: &str) {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    with_entries(|entries| entries.retain(|key, _| !key.starts_with(prefix)));
+}
+
+impl Store {
+    /// Reads string keys from redis through the cache, returning a value (possibly nil) for
+    /// each.  A sqlite store is read by its own methods instead.
+    pub(super) fn cached_get(&self, keys: &[String]) -> Result<Vec<Value>, Error> {
+        if self.cache_ttl == Duration::from_secs(0) || !LISTENING.load(Ordering::SeqCst) {
+            return self.fetch(keys);
+        }
+
+        let now = Instant::now();
+        let mut found = with_entries(|entries| keys.iter().map(|key| match entries.get(key) {
+            Some(&(stored, ref value)) if now.duration_since(stored) < self.cache_ttl => Some(value.clone()),
+            _ => None
+        }).collect::<Vec<_>>());
+        let missing = keys.iter().zip(found.iter()).filter(|&(_, value)| value.is_none())
+            .map(|(key, _)| key.clone()).collect::<Vec<_>>();
+        metrics::cache(keys.len() - missing.len(), missing.len());
+        if missing.is_empty() { return Ok(found.into_iter().map(|v| v.unwrap_or(Value::Nil)).collect()); }
+
+        let generation = GENERATION.load(Ordering::SeqCst);
+        let mut fetched = self.fetch(&missing)?.into_iter();
+        with_entries(|entries| {
+            let current = GENERATION.load(Ordering::SeqCst) == generation;
+            if current { make_room(entries, missing.len(), now, self.cache_ttl); }
+            for (key, value) in keys.iter().zip(found.iter_mut()) {
+                if value.is_some() { continue; }
+                let fresh = fetched.next().unwrap_or(Value::Nil);
+                if current { entries.insert(key.clone(), (now, fresh.clone())); }
+                *value = Some(fresh);
+            }
+        });
+        Ok(found.into_iter().map(|v| v.unwrap_or(Value::Nil)).collect())
+    }
+
+    fn fetch(&self, keys: &[String]) -> Result<Vec<Value>, Error> {
+        if keys.len() == 1 {
+            self.call("get", |c| c.get(&keys[0][..])).map(|value| vec![value])
+        } else {
+            self.call("mget", |c| redis::cmd("MGET").arg(keys).query(c))
+        }
+    }
+
+    /// Drops every key starting with `prefix` from the cache of every process, after a write.
+    /// If the invalidation can't be published, other processes see the change once their
+    /// cached value expires.
+    pub(super) fn invalidate(&self, prefix: &str) {
+        forget(prefix);
+        if self.sqlite().is_some() { return; }
+        if let Err(e) = self.call("publish", |c| c.publish(self.key(CHANNEL), prefix)).map(|_: u32| ()) {
+            warn!("Could not publish a cache invalidation for {}: {}", prefix, e);
+        }
+    }
+}
+
+/// Subscribes to invalidations in the background; the cache is only used while subscribed.
+/// If the subscription is lost, it is made again, and the whole cache is dropped, since any
+/// invalidations sent meanwhile were missed.  A sqlite store is read directly, and not cached.
+pub fn listen_for_invalidations(config: &Bot) -> Result<(), Error> {
+    if config.cache_ttl == 0 { return Ok(()); }
+    let store = Store::from(config)?;
+    let client = match store.backend {
+        Backend::Redis { ref client, .. } => client.clone(),
+        Backend::Sqlite(_) => return Ok(())
+    };
+    let channel = store.key(CHANNEL);
+    thread::Builder::new().name(String::from("cache")).spawn(move || loop {
+        if let Err(e) = subscribe(&client, &channel) {
+            warn!("Lost the cache invalidation subscription; the cache is off until it is back: {}", e);
+        }
+        LISTENING.store(false, Ordering::SeqCst);
+        thread::sleep(Duration::from_secs(1));
+    })?;
+    Ok(())
+}
+
+fn subscribe(client: &Client, channel: &str) -> Result<(), Error> {
+    let mut pubsub = client.get_pubsub()?;
+    pubsub.subscribe(channel)?;
+    forget("");
+    LISTENING.store(true, Ordering::SeqCst);
+    debug!("Subscribed to cache invalidations on {}.", channel);
+    loop {
+        let prefix: String = pubsub.get_message()?.get_payload()?;
+        trace!("Invalidating cached keys under {}...", prefix);
+        forget(&prefix);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use redis::Value;
+    use super::{make_room, MAX_ENTRIES};
+
+    #[test]
+    fn make_room_drops_expired_entries_first() {
+        let then = Instant::now();
+        let now = then + Duration::from_secs(120);
+        let ttl = Duration::from_secs(60);
+        let mut entries = HashMap::new();
+        for i in 0..MAX_ENTRIES - 1 {
+            entries.insert(format!("expired:{}", i), (then, Value::Nil));
+        }
+        entries.insert(String::from("live"), (now, Value::Nil));
+
+        make_room(&mut entries, 0, now, ttl);
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        make_room(&mut entries, 1, now, ttl);
+        assert_eq!(entries.keys().collect::<Vec<_>>(), vec!["live"]);
+    }
+
+    #[test]
+    fn make_room_clears_a_cache_full_of_live_entries() {
+        let now = Instant::now();
+        let mut entries = (0..MAX_ENTRIES).map(|i| (i.to_string(), (now, Value::Nil))).collect::<HashMap<_, _>>();
+        make_room(&mut entries, 1, now, Duration::from_secs(60));
+        assert!(entries.is_empty());
+    }
+}
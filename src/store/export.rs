@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Error as FmtError};
+use redis::{self, Commands, PipelineCommands};
+use serde_json;
+use shard::plugs::configuration::{module, setting};
+use shard::plugs::configuration::setting::Stored;
+use super::{migrations, Error, Scope, Store};
+
+/// The version of the export format written by `Store::server_export`.  Version 2 stores
+/// settings with their kind; version 1 exports are upgraded as they are read, and imports of
+/// any other version are refused.
+pub static EXPORT_VERSION: u32 = 2;
+
+/// Every key stored for a server, i.e. everything under `server:{id}:`, with the keys given
+/// relative to that prefix (e.g. `modules:admin.log:enabled`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Export {
+    pub version: u32,
+    pub server: String,
+    pub keys: BTreeMap<String, Entry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum Entry {
+    String(String),
+    List(Vec<String>),
+    Hash(BTreeMap<String, String>),
+    Set(Vec<String>),
+}
+
+/// A key in an export that the store won't import, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejected {
+    pub key: String,
+    pub problem: String,
+}
+
+/// A key relative to a server's prefix, as the store lays them out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Key {
+    /// `modules:{module}:enabled`, or under `channels:{channel}:`
+    Module(Scope, String),
+    /// `settings:{setting}`, or under `channels:{channel}:` or `users:{user}:`
+    Setting(Scope, String),
+    /// `aliases`
+    Aliases,
+}
+
+impl Key {
+    pub(super) fn parse(key: &str) -> Option<Key> {
+        if key == "aliases" { return Some(Key::Aliases); }
+        if key.starts_with("modules:") && key.ends_with(":enabled") && key.len() > 16 {
+            return Some(Key::Module(Scope::Server, key[8..key.len() - 8].to_owned()));
+        }
+        if key.starts_with("settings:") { return Some(Key::Setting(Scope::Server, key[9..].to_owned())); }
+
+        let parts = key.splitn(4, ':').collect::<Vec<_>>();
+        match (parts.get(0), parts.get(1).and_then(|t| t.parse().ok()), parts.get(2), parts.get(3)) {
+            (Some(&"channels"), Some(channel), Some(&"modules"), Some(flag)) if flag.ends_with(":enabled") && flag.len() > 8 =>
+                Some(Key::Module(Scope::Channel(channel), flag[..flag.len() - 8].to_owned())),
+            (Some(&"channels"), Some(channel), Some(&"settings"), Some(name)) =>
+                Some(Key::Setting(Scope::Channel(channel), name.to_string())),
+            (Some(&"users"), Some(user), Some(&"settings"), Some(name)) =>
+                Some(Key::Setting(Scope::User(user), name.to_string())),
+            _ => None
+        }
+    }
+}
+
+/// How an import treats keys that are stored, but not in the export.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Keeps them.
+    Merge,
+    /// Deletes them, so that the server ends up exactly as exported.
+    Replace,
+}
+
+/// A single difference between what is stored and what an import would store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added(String, Entry),
+    Changed(String, Entry, Entry),
+    Removed(String, Entry),
+}
+
+impl Export {
+    pub fn from_json(json: &str) -> Result<Export, Error> {
+        let mut export = serde_json::from_str::<Export>(json)
+            .map_err(|e| Error::ConfigurationError(format!("not a valid export: {}", e)))?;
+        if export.version == 1 { export.upgrade()?; }
+        if export.version != EXPORT_VERSION {
+            return Err(Error::ConfigurationError(
+                format!("exports of version {} can't be imported; expected version {}", export.version, EXPORT_VERSION)));
+        }
+        Ok(export)
+    }
+
+    /// Rewrites the settings of a version 1 export with their kind, as migration 2 does to
+    /// the store.
+    fn upgrade(&mut self) -> Result<(), Error> {
+        let mut upgraded = BTreeMap::new();
+        for (key, entry) in &self.keys {
+            if let Some(Key::Setting(_, name)) = Key::parse(key) {
+                if let Some(value) = migrations::typed_setting(&name, entry.clone())? {
+                    upgraded.insert(key.clone(), Entry::String(value));
+                }
+            }
+        }
+        self.keys.extend(upgraded);
+        self.version = 2;
+        Ok(())
+    }
+
+    /// The keys that can't be imported: those the store doesn't write, modules and settings
+    /// that don't exist or can't be set in their scope, and values of the wrong kind.
+    pub fn rejected(&self) -> Vec<Rejected> {
+        self.keys.iter().filter_map(|(key, entry)| check(key, entry).err()
+            .map(|problem| Rejected { key: key.clone(), problem })).collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_else(|_| String::from("{}"))
+    }
+
+    /// The changes importing this over `current` would make.
+    pub fn diff(&self, current: &Export, mode: ImportMode) -> Vec<Change> {
+        let mut changes = vec![];
+        for (key, entry) in &self.keys {
+            match current.keys.get(key) {
+                None => changes.push(Change::Added(key.clone(), entry.clone())),
+                Some(old) if old != entry => changes.push(Change::Changed(key.clone(), old.clone(), entry.clone())),
+                Some(_) => {}
+            }
+        }
+        if mode == ImportMode::Replace {
+            for (key, entry) in &current.keys {
+                if !self.keys.contains_key(key) { changes.push(Change::Removed(key.clone(), entry.clone())); }
+            }
+        }
+        changes
+    }
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            &Entry::String(ref value) => write!(f, "{:?}", value),
+            &Entry::List(ref values) | &Entry::Set(ref values) => write!(f, "{:?}", values),
+            &Entry::Hash(ref values) => write!(f, "{:?}", values)
+        }
+    }
+}
+
+impl Display for Rejected {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "! {} ({})", self.key, self.problem)
+    }
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            &Change::Added(ref key, ref entry) => write!(f, "+ {} = {}", key, entry),
+            &Change::Changed(ref key, ref old, ref new) => write!(f, "~ {} = {} (was {})", key, new, old),
+            &Change::Removed(ref key, ref entry) => write!(f, "- {} (was {})", key, entry)
+        }
+    }
+}
+
+impl Store {
+    /// Reads every key stored for a server.
+    pub fn server_export(&self, server: u64) -> Result<Export, Error> {
+        if let Some(sqlite) = self.sqlite() {
+            return Ok(Export { version: EXPORT_VERSION, server: server.to_string(), keys: sqlite.server_export(server)? });
+        }
+        let prefix = self.key(&server_prefix(server));
+        let mut keys = BTreeMap::new();
+        for key in self.scan(&format!("{}*", prefix))? {
+            if let Some(entry) = self.entry(&key)? { keys.insert(key[prefix.len()..].to_owned(), entry); }
+        }
+        Ok(Export { version: EXPORT_VERSION, server: server.to_string(), keys })
+    }
+
+    /// Reads a key of any type; `None` if it has expired, or is a type we never write.
+    pub(super) fn entry(&self, key: &str) -> Result<Option<Entry>, Error> {
+        let kind: String = self.call("type", |c| redis::cmd("TYPE").arg(key).query(c))?;
+        Ok(Some(match &kind[..] {
+            "string" => Entry::String(self.call("get", |c| c.get(key))?),
+            "list" => Entry::List(self.call("lrange", |c| c.lrange(key, 0, -1))?),
+            "hash" => Entry::Hash(self.call("hgetall", |c| c.hgetall(key))?),
+            "set" => Entry::Set(self.call("smembers", |c| c.smembers(key))?),
+            _ => return Ok(None)
+        }))
+    }
+
+    /// Writes an export to a server, all at once, and returns the changes made.  The export
+    /// may come from a different server.  Nothing is written if any key is rejected.
+    pub fn server_import(&self, server: u64, export: &Export, mode: ImportMode) -> Result<Vec<Change>, Error> {
+        let rejected = export.rejected();
+        if !rejected.is_empty() {
+            let keys = rejected.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+            return Err(Error::ConfigurationError(format!("the export has keys that can't be imported: {}", keys.join(", "))));
+        }
+        let prefix = self.key(&server_prefix(server));
+        let changes = export.diff(&self.server_export(server)?, mode);
+        if let Some(sqlite) = self.sqlite() {
+            sqlite.server_import(&prefix, &changes)?;
+            self.invalidate(&prefix);
+            return Ok(changes);
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for change in &changes {
+            let (key, entry) = match change {
+                &Change::Removed(ref key, _) => { pipe.del(format!("{}{}", prefix, key)).ignore(); continue; },
+                &Change::Added(ref key, ref entry) | &Change::Changed(ref key, _, ref entry) =>
+                    (format!("{}{}", prefix, key), entry)
+            };
+            pipe.del(&key[..]).ignore();
+            match entry {
+                &Entry::String(ref value) => { pipe.set(&key[..], &value[..]).ignore(); },
+                &Entry::List(ref values) if !values.is_empty() => { pipe.rpush(&key[..], values.clone()).ignore(); },
+                &Entry::Set(ref values) if !values.is_empty() => { pipe.sadd(&key[..], values.clone()).ignore(); },
+                &Entry::Hash(ref values) if !values.is_empty() => {
+                    let pairs = values.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+                    pipe.hset_multiple(&key[..], &pairs).ignore();
+                },
+                _ => {}
+            }
+        }
+        if !changes.is_empty() {
+            self.call_once("pipeline", |c| pipe.query::<()>(c))?;
+            self.invalidate(&prefix);
+        }
+        Ok(changes)
+    }
+
+    /// Deletes every key stored for a server, its usage statistics, and its history.  Returns
+    /// the number of keys deleted.
+    pub fn server_purge(&self, server: u64) -> Result<usize, Error> {
+        let prefix = self.key(&server_prefix(server));
+        if let Some(sqlite) = self.sqlite() {
+            let deleted = sqlite.server_purge(server)?;
+            self.invalidate(&prefix);
+            return Ok(deleted);
+        }
+        let mut keys = self.scan(&format!("{}*", prefix))?;
+        keys.append(&mut self.scan(&self.key(&format!("stats:server:{}:*", server)))?);
+        keys.push(self.history_key(server));
+        let mut deleted = 0;
+        for batch in keys.chunks(100) {
+            let removed: u32 = self.call("del", |c| c.del(batch))?;
+            deleted += removed as usize;
+        }
+        self.invalidate(&prefix);
+        Ok(deleted)
+    }
+
+    /// Every server with anything stored for it.
+    pub fn servers(&self) -> Result<Vec<u64>, Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.servers(); }
+        let prefix = self.key("server:");
+        let mut servers = self.scan(&format!("{}*", prefix))?.iter()
+            .filter_map(|key| key[prefix.len()..].split(':').next().and_then(|id| id.parse().ok()))
+            .collect::<Vec<u64>>();
+        servers.sort();
+        servers.dedup();
+        Ok(servers)
+    }
+}
+
+fn server_prefix(server: u64) -> String { format!("server:{}:", server) }
+
+/// Checks a key of an export, and the entry it holds, against the modules and settings that
+/// exist.
+fn check(key: &str, entry: &Entry) -> Result<(), String> {
+    match (Key::parse(key), entry) {
+        (None, _) => Err(String::from("not a key the store writes")),
+        (Some(Key::Module(_, name)), entry) => {
+            if module::find(&name).is_none() { return Err(format!("no module named {}", name)); }
+            match entry {
+                &Entry::String(ref flag) if flag == "0" || flag == "1" => Ok(()),
+                _ => Err(String::from("expected \"0\" or \"1\""))
+            }
+        },
+        (Some(Key::Setting(scope, name)), entry) => {
+            let setting = setting::find(&name).ok_or_else(|| format!("no setting named {}", name))?;
+            if !setting.allows(scope) { return Err(format!("setting {} can't be set per {:?}", name, scope.kind())); }
+            match entry {
+                &Entry::String(ref raw) => match setting.decode(Some(raw.clone())) {
+                    Stored::Corrupt(_, problem) => Err(problem),
+                    _ => Ok(())
+                },
+                _ => Err(String::from("expected a string"))
+            }
+        },
+        (Some(Key::Aliases), &Entry::Hash(_)) => Ok(()),
+        (Some(Key::Aliases), _) => Err(String::from("expected a hash"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use store::{Backend, Store};
+    use store::sqlite::Sqlite;
+    use super::{Entry, Export, ImportMode, Rejected, EXPORT_VERSION};
+
+    fn memory() -> Store {
+        let sqlite = Sqlite::open(":memory:", Duration::from_secs(1)).unwrap();
+        Store { backend: Backend::Sqlite(sqlite), prefix: String::new(), cache_ttl: Duration::from_secs(1) }
+    }
+
+    fn export(keys: &[(&str, Entry)]) -> Export {
+        Export { version: EXPORT_VERSION, server: String::from("1"),
+            keys: keys.iter().map(|&(ref key, ref entry)| (key.to_string(), entry.clone())).collect() }
+    }
+
+    fn string(value: &str) -> Entry { Entry::String(value.to_owned()) }
+
+    #[test]
+    fn version_1_exports_are_upgraded() {
+        let export = Export::from_json(r#"{
+            "version": 1,
+            "server": "1",
+            "keys": {
+                "modules:admin.log:enabled": {"type": "string", "value": "1"},
+                "settings:test.int": {"type": "string", "value": "5"},
+                "settings:test.str": {"type": "string", "value": "{\"type\":\"string\",\"value\":\"typed\"}"},
+                "channels:2:settings:test.ary": {"type": "list", "value": ["a", "b"]},
+                "users:3:settings:test.user": {"type": "string", "value": "4"},
+                "settings:test.role": {"type": "string", "value": "not a role"}
+            }
+        }"#).unwrap();
+        assert_eq!(export.version, EXPORT_VERSION);
+        assert_eq!(export.keys["modules:admin.log:enabled"], string("1"));
+        assert_eq!(export.keys["settings:test.int"], string(r#"{"type":"integer","value":5}"#));
+        assert_eq!(export.keys["settings:test.str"], string(r#"{"type":"string","value":"typed"}"#));
+        assert_eq!(export.keys["channels:2:settings:test.ary"], string(r#"{"type":"array","value":["a","b"]}"#));
+        assert_eq!(export.keys["users:3:settings:test.user"], string(r#"{"type":"user","value":4}"#));
+        assert_eq!(export.keys["settings:test.role"], string("not a role"));
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        assert!(Export::from_json(r#"{"version": 3, "server": "1", "keys": {}}"#).is_err());
+        assert!(Export::from_json(r#"{"version": 0, "server": "1", "keys": {}}"#).is_err());
+    }
+
+    #[test]
+    fn rejected_lists_keys_that_dont_fit_the_registries() {
+        let export = export(&[
+            ("modules:admin.log:enabled", string("1")),
+            ("channels:2:modules:commands:enabled", string("0")),
+            ("settings:test.int", string(r#"{"type":"integer","value":5}"#)),
+            ("users:3:settings:test.user", string(r#"{"type":"user","value":4}"#)),
+            ("aliases", Entry::Hash(BTreeMap::new())),
+            ("modules:nonexistent:enabled", string("1")),
+            ("modules:test:enabled", string("yes")),
+            ("settings:nonexistent", string(r#"{"type":"string","value":"a"}"#)),
+            ("channels:2:settings:test.role", string(r#"{"type":"role","value":4}"#)),
+            ("settings:test.channel", string(r#"{"type":"string","value":"a"}"#)),
+            ("settings:test.str", Entry::List(vec![String::from("a")])),
+            ("prefix", string("!")),
+        ]);
+        let keys = export.rejected().into_iter().map(|Rejected { key, .. }| key).collect::<Vec<_>>();
+        assert_eq!(keys, vec!["channels:2:settings:test.role", "modules:nonexistent:enabled", "modules:test:enabled",
+            "prefix", "settings:nonexistent", "settings:test.channel", "settings:test.str"]);
+    }
+
+    #[test]
+    fn imports_with_rejected_keys_change_nothing() {
+        let store = memory();
+        let valid = ("settings:test.int", string(r#"{"type":"integer","value":5}"#));
+        let invalid = ("settings:test.channel", string(r#"{"type":"string","value":"a"}"#));
+        assert!(store.server_import(1, &export(&[valid.clone(), invalid]), ImportMode::Merge).is_err());
+        assert!(store.server_export(1).unwrap().keys.is_empty());
+
+        let changes = store.server_import(1, &export(&[valid]), ImportMode::Merge).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(store.server_export(1).unwrap().keys["settings:test.int"], string(r#"{"type":"integer","value":5}"#));
+    }
+}
@@ -0,0 +1,53 @@
+use redis::{self, Commands, PipelineCommands};
+use serde_json;
+use super::{Error, Scope, Store};
+
+/// The most changes kept in a server's history; older ones are dropped as new ones are added.
+pub static HISTORY_LIMIT: usize = 500;
+
+/// A change made to a server's modules or settings, and who made it.  Values are kept as they
+/// were shown to the person making the change, in the scope changed; `None` means unset, i.e.
+/// inherited from the server, or the module's default.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// When the change was made, in seconds since the epoch.
+    pub time: u64,
+    /// The user who made the change; for a change made through the admin API, the holder of
+    /// its token, or 0 if none is configured.
+    pub actor: u64,
+    /// The `configure` action, e.g. `module.disable` or `setting.push`.
+    pub action: String,
+    /// The module or setting that was changed.
+    pub name: String,
+    /// The scope the change was made in; `None` for module changes recorded before modules
+    /// could be changed per channel.
+    pub scope: Option<Scope>,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl Store {
+    /// Adds a change to the server's history, dropping the oldest beyond `HISTORY_LIMIT`.
+    pub fn history_record(&self, server: u64, record: &Record) -> Result<(), Error> {
+        let json = serde_json::to_string(record)?;
+        if let Some(sqlite) = self.sqlite() { return sqlite.history_record(server, record.time, &json, HISTORY_LIMIT); }
+        let key = self.history_key(server);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .lpush(&key[..], &json[..]).ignore()
+            .ltrim(&key[..], 0, HISTORY_LIMIT as isize - 1).ignore();
+        self.call_once("pipeline", |c| pipe.query(c))
+    }
+
+    /// The server's history, newest first.  Entries that can't be read are skipped.
+    pub fn history_read(&self, server: u64) -> Result<Vec<Record>, Error> {
+        let entries: Vec<String> = match self.sqlite() {
+            Some(sqlite) => sqlite.history_read(server)?,
+            None => self.call("lrange", |c| c.lrange(self.history_key(server), 0, -1))?
+        };
+        Ok(entries.iter().filter_map(|json| match serde_json::from_str(json) {
+            Ok(record) => Some(record),
+            Err(e) => { warn!("Skipping an unreadable history entry for server {}: {}", server, e); None }
+        }).collect())
+    }
+}
@@ -0,0 +1,127 @@
+use redis::{self, Commands, PipelineCommands};
+use serde_json;
+use shard::plugs::configuration::setting::{self, SettingValue};
+use super::{sqlite, Entry, Error, Store};
+
+/// A single step in the store's key layout.  Steps must be idempotent: if a migration is
+/// interrupted, the step is run again from the start.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    /// Runs the step, or only counts the keys it would change if `dry_run` is set.  Returns
+    /// the number of keys changed.
+    run: fn(&Store, bool) -> Result<usize, Error>,
+}
+
+/// Every migration, in order; the version of each is its position, counting from 1.  These
+/// change redis keys; a sqlite store has versions and migrations of its own.
+static MIGRATIONS: &'static [Migration] = &[
+    Migration { version: 1, description: "record the schema version", run: baseline },
+    Migration { version: 2, description: "store settings with their kind", run: typed_settings },
+];
+
+/// The schema version this build expects the store to be at; the version of the last migration.
+pub static SCHEMA_VERSION: u32 = 2;
+
+pub(super) static VERSION_KEY: &'static str = "schema:version";
+
+/// A migration that was (or would have been) applied.
+#[derive(Debug, Clone)]
+pub struct Migrated {
+    pub version: u32,
+    pub description: &'static str,
+    pub changed: usize,
+}
+
+/// The layout as it was before versions were recorded; there is nothing to change.
+fn baseline(_: &Store, _: bool) -> Result<usize, Error> { Ok(0) }
+
+/// Rewrites every setting as a `SettingValue`.  Values already rewritten are left alone.
+fn typed_settings(store: &Store, dry_run: bool) -> Result<usize, Error> {
+    let mut changed = 0;
+    for key in store.scan(&store.key("server:*:settings:*"))? {
+        let name = match key.find(":settings:") { Some(index) => &key[index + 10..], None => continue };
+        let entry = match store.entry(&key)? { Some(entry) => entry, None => continue };
+        let value = match typed_setting(name, entry)? { Some(value) => value, None => continue };
+        changed += 1;
+        if !dry_run {
+            store.call("pipeline", |c| redis::pipe().atomic()
+                .del(&key[..]).ignore()
+                .set(&key[..], &value[..]).ignore()
+                .query::<()>(c))?;
+        }
+    }
+    Ok(changed)
+}
+
+/// The value a setting stored before settings were typed is rewritten to, or `None` if it
+/// needs no rewriting.  Values that don't fit their setting, or that belong to no setting,
+/// are rewritten as a plain string (or a JSON array, for lists) so that they are reported as
+/// corrupt when read.
+pub(super) fn typed_setting(name: &str, entry: Entry) -> Result<Option<String>, Error> {
+    if let Entry::String(ref raw) = entry {
+        if serde_json::from_str::<SettingValue>(raw).is_ok() { return Ok(None); }
+    }
+
+    let upgraded = setting::find(name).and_then(|setting| setting.upgrade(entry.clone()));
+    Ok(Some(match (upgraded, entry) {
+        (Some(value), _) => serde_json::to_string(&value)?,
+        (None, Entry::String(_)) => return Ok(None),
+        (None, Entry::List(values)) | (None, Entry::Set(values)) => serde_json::to_string(&values)?,
+        (None, Entry::Hash(values)) => serde_json::to_string(&values)?
+    }))
+}
+
+impl Store {
+    /// The recorded schema version; 0 if none has been recorded.
+    pub fn schema_version(&self) -> Result<u32, Error> {
+        if let Some(sqlite) = self.sqlite() {
+            return Ok(sqlite.meta_get(VERSION_KEY)?.and_then(|v| v.parse().ok()).unwrap_or(0));
+        }
+        self.call("get", |c| c.get(self.key(VERSION_KEY))).map(|v: Option<u32>| v.unwrap_or(0))
+    }
+
+    /// The schema version this build expects of the store.
+    pub fn expected_version(&self) -> u32 {
+        if self.sqlite().is_some() { sqlite::SCHEMA_VERSION } else { SCHEMA_VERSION }
+    }
+
+    /// Runs every migration past the recorded version, in order, recording the version after
+    /// each.  With `dry_run`, nothing is changed.
+    pub fn migrate(&self, dry_run: bool) -> Result<Vec<Migrated>, Error> {
+        let current = self.schema_version()?;
+        if let Some(sqlite) = self.sqlite() { return sqlite.migrate(current, dry_run); }
+        let mut migrated = vec![];
+        for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+            info!("Migrating the store to version {}: {}...", migration.version, migration.description);
+            let changed = (migration.run)(self, dry_run)?;
+            if !dry_run { self.set_version(migration.version)?; }
+            migrated.push(Migrated { version: migration.version, description: migration.description, changed });
+        }
+        if !dry_run && migrated.iter().any(|m| m.changed > 0) { self.invalidate(&self.key("")); }
+        Ok(migrated)
+    }
+
+    /// Checks that the store is at the schema version this build expects.  A store with no
+    /// servers in it has nothing to migrate, so it is simply marked as current.
+    pub fn check_schema(&self) -> Result<(), Error> {
+        let (version, expected) = (self.schema_version()?, self.expected_version());
+        if version == 0 && self.servers()?.is_empty() {
+            return self.set_version(expected);
+        }
+        if version < expected {
+            Err(Error::ConfigurationError(format!(
+                "the store is at schema version {}, but version {} is needed; run `wonderful migrate`",
+                version, expected)))
+        } else if version > expected {
+            Err(Error::ConfigurationError(format!(
+                "the store is at schema version {}, which is newer than this build's version {}",
+                version, expected)))
+        } else { Ok(()) }
+    }
+
+    fn set_version(&self, version: u32) -> Result<(), Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.meta_set(VERSION_KEY, &version.to_string()); }
+        self.call("set", |c| c.set(self.key(VERSION_KEY), version))
+    }
+}
@@ -1,78 +1,503 @@
 use super::Error;
+use configuration::Bot;
+use metrics;
 use redis;
-use redis::{Client, Commands, PipelineCommands};
-use std::ops::Deref;
+use redis::{IntoConnectionInfo, Client, Commands, Connection, ErrorKind, PipelineCommands, RedisError, RedisResult};
+use rusqlite::{Error as SqliteError, ErrorCode};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Error as FmtError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug)]
-pub struct Store(Client);
+mod cache;
+mod export;
+mod history;
+mod migrations;
+mod schedule;
+mod sqlite;
+
+pub use self::cache::listen_for_invalidations;
+pub use self::export::{Change, Entry, Export, ImportMode, Rejected, EXPORT_VERSION};
+pub use self::history::Record;
+pub use self::migrations::{Migrated, SCHEMA_VERSION};
+use self::sqlite::Sqlite;
+
+/// How long, in seconds, redis has to answer a command before it fails.
+static TIMEOUT: u64 = 5;
+/// How many times a command that is safe to repeat is tried, when redis can't be reached.
+static ATTEMPTS: u32 = 3;
+/// The most connections kept open while unused.
+static MAX_IDLE: usize = 4;
+
+/// A handle on the store, which is either redis or, for a `sqlite://path` uri, a sqlite
+/// database.  Redis connections are opened as they are needed and kept for reuse; one that
+/// fails is dropped, and replaced on the next call.  Every key is stored under the configured
+/// prefix.  Module flags and settings in redis are read through the process's cache.
+pub struct Store { backend: Backend, prefix: String, cache_ttl: Duration }
+
+enum Backend {
+    Redis { client: Client, idle: Mutex<Vec<Connection>> },
+    Sqlite(Sqlite),
+}
+
+impl Debug for Store {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self.backend {
+            Backend::Redis { ref client, .. } => write!(f, "Store({:?}, {:?})", client, self.prefix),
+            Backend::Sqlite(ref sqlite) => write!(f, "Store(sqlite://{})", sqlite.path())
+        }
+    }
+}
+
+/// The path in a `sqlite://path` store uri, if it is one.
+pub fn sqlite_path(uri: &str) -> Option<&str> {
+    if uri.starts_with("sqlite://") { Some(&uri[9..]) } else { None }
+}
+
+/// Where in a server a setting is stored.  Settings are always stored for a server; a channel
+/// or user scope overrides the server's value in that channel, or for that user.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "type", content = "id", rename_all = "lowercase")]
+pub enum Scope {
+    Server,
+    Channel(u64),
+    User(u64),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ScopeKind {
+    Server, Channel, User
+}
+
+impl Scope {
+    pub fn kind(&self) -> ScopeKind {
+        match self {
+            &Scope::Server => ScopeKind::Server,
+            &Scope::Channel(_) => ScopeKind::Channel,
+            &Scope::User(_) => ScopeKind::User
+        }
+    }
+
+    /// The scopes a setting is read from in a channel, for a user, most specific first.
+    pub fn resolution(channel: Option<u64>, user: Option<u64>) -> Vec<Scope> {
+        let mut scopes = vec![];
+        if let Some(user) = user { scopes.push(Scope::User(user)); }
+        if let Some(channel) = channel { scopes.push(Scope::Channel(channel)); }
+        scopes.push(Scope::Server);
+        scopes
+    }
+}
+
+/// Whether the error is a failure to reach redis, or redis not being ready yet, rather than a
+/// problem with the command; i.e. whether trying again later might work.
+pub fn is_transient(error: &RedisError) -> bool {
+    match error.kind() {
+        ErrorKind::IoError | ErrorKind::BusyLoadingError => true,
+        _ => false
+    }
+}
+
+/// Whether the error is sqlite giving up on a database that another connection kept locked
+/// past the busy timeout; i.e. whether trying again later might work.
+pub fn is_busy(error: &SqliteError) -> bool {
+    match error {
+        &SqliteError::SqliteFailure(ref failure, _) =>
+            failure.code == ErrorCode::DatabaseBusy || failure.code == ErrorCode::DatabaseLocked,
+        _ => false
+    }
+}
 
 impl Store {
-    pub fn from(config: &str) -> Result<Store, Error> {
-        Ok(Store(Client::open(config)?))
+    /// Opens the store described by `store`, `store_db`, and `store_prefix`.  No connection is
+    /// made to redis until the store is first used; a sqlite database is opened, and created if
+    /// need be, at once.  `store_db` and `store_prefix` only apply to redis.
+    pub fn from(config: &Bot) -> Result<Store, Error> {
+        let cache_ttl = Duration::from_secs(config.cache_ttl);
+        if let Some(path) = sqlite_path(&config.store) {
+            let sqlite = Sqlite::open(path, Duration::from_secs(TIMEOUT))?;
+            return Ok(Store { backend: Backend::Sqlite(sqlite), prefix: String::new(), cache_ttl });
+        }
+        let mut info = (&config.store[..]).into_connection_info()?;
+        if let Some(db) = config.store_db { info.db = db; }
+        let backend = Backend::Redis { client: Client::open(info)?, idle: Mutex::new(vec![]) };
+        Ok(Store { backend, prefix: config.store_prefix.clone(), cache_ttl })
+    }
+
+    fn sqlite(&self) -> Option<&Sqlite> {
+        match self.backend { Backend::Sqlite(ref sqlite) => Some(sqlite), _ => None }
+    }
+
+    /// Sets a string key in redis.
+    fn set_string(&self, key: &str, value: &str) -> Result<(), Error> {
+        self.call("set", |c| c.set(key, value))?;
+        self.invalidate(key);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        self.call("del", |c| c.del(key))?;
+        self.invalidate(key);
+        Ok(())
     }
 
     // pub fn find_prefix_for(&self, server: u64) -> Result<Option<String>, Error> {
     //     self.0.get(format!("server:{}:prefix", server)).map_err(|e| e.into())
     // }
 
-    pub fn module_enable(&self, server: u64, module: &str) -> Result<(), Error> {
-        self.0.set(module_enabled_key(server, module), 1).map_err(|e| e.into())
+    pub fn ping(&self) -> Result<String, Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.ping(); }
+        self.call("ping", |c| redis::cmd("PING").query(c))
+    }
+
+    /// Modules may be turned on or off for a server, or for a channel in it; not for a user.
+    pub fn module_enable(&self, server: u64, scope: Scope, module: &str) -> Result<(), Error> {
+        self.module_set(server, scope, module, true)
     }
 
-    pub fn module_disable(&self, server: u64, module: &str) -> Result<(), Error> {
-        self.0.set(module_enabled_key(server, module), 0).map_err(|e| e.into())
+    pub fn module_disable(&self, server: u64, scope: Scope, module: &str) -> Result<(), Error> {
+        self.module_set(server, scope, module, false)
     }
 
-    pub fn module_clear(&self, server: u64, module: &str) -> Result<(), Error> {
-        self.0.del(module_enabled_key(server, module)).map_err(|e| e.into())
+    fn module_set(&self, server: u64, scope: Scope, module: &str, enabled: bool) -> Result<(), Error> {
+        let key = self.module_enabled_key(server, scope, module)?;
+        if let Some(sqlite) = self.sqlite() { return sqlite.module_set(server, scope, module, enabled); }
+        self.set_string(&key, if enabled { "1" } else { "0" })
     }
 
-    pub fn module_is_enabled(&self, server: u64, module: &str) -> Result<Option<bool>, Error> {
-        self.0.get(module_enabled_key(server, module))
-            .map(|vopt: Option<u32>| vopt.map(|v| v != 0)).map_err(|e| -> Error { e.into() })
+    pub fn module_clear(&self, server: u64, scope: Scope, module: &str) -> Result<(), Error> {
+        let key = self.module_enabled_key(server, scope, module)?;
+        if let Some(sqlite) = self.sqlite() { return sqlite.module_clear(server, scope, module); }
+        self.delete(&key)
     }
 
-    pub fn module_check_enabled(&self, server: u64, module: &str, default: bool) -> Result<bool, Error> {
-        let result: Option<u32> = self.0.get(module_enabled_key(server, module))
-            .map_err(|e| -> Error { e.into() })?;
-        let result = result.map(|v| v != 0).unwrap_or(default);
-        Ok(result)
+    /// The flag stored in exactly the given scope, if any.
+    pub fn module_is_enabled(&self, server: u64, scope: Scope, module: &str) -> Result<Option<bool>, Error> {
+        Ok(self.module_get(server, &[scope], module)?.pop().unwrap_or(None))
     }
 
-    pub fn setting_get<T: redis::FromRedisValue>(&self, server: u64, setting: &str) -> Result<Option<T>, Error> {
-        self.0.get(setting_key(server, setting)).map_err(|e| e.into())
+    /// Whether the module is on in the channel, if any: the channel's flag, then the server's,
+    /// then the default.
+    pub fn module_check_enabled(&self, server: u64, channel: Option<u64>, module: &str, default: bool) -> Result<bool, Error> {
+        let flags = self.module_get(server, &Scope::resolution(channel, None), module)?;
+        Ok(flags.into_iter().filter_map(|flag| flag).next().unwrap_or(default))
     }
 
-    pub fn setting_get_array(&self, server: u64, setting: &str) -> Result<Vec<String>, Error> {
-        self.0.lrange(setting_key(server, setting), 0, -1).map_err(|e| e.into())
+    /// The flag stored in each of the given scopes at once, in the same order.
+    fn module_get(&self, server: u64, scopes: &[Scope], module: &str) -> Result<Vec<Option<bool>>, Error> {
+        let keys = scopes.iter().map(|scope| self.module_enabled_key(server, *scope, module))
+            .collect::<Result<Vec<_>, Error>>()?;
+        if let Some(sqlite) = self.sqlite() { return sqlite.module_get(server, scopes, module); }
+        let flags: Vec<Option<u32>> = self.cached_get(&keys)?.iter().map(redis::from_redis_value).collect::<RedisResult<_>>()?;
+        Ok(flags.into_iter().map(|flag| flag.map(|v| v != 0)).collect())
     }
 
-    pub fn setting_set<T: redis::ToRedisArgs>(&self, server: u64, setting: &str, value: T) -> Result<(), Error> {
-        self.0.set(setting_key(server, setting), value).map_err(|e| e.into())
+    pub fn setting_get(&self, server: u64, scope: Scope, setting: &str) -> Result<Option<String>, Error> {
+        Ok(self.setting_get_scopes(server, &[scope], setting)?.pop().unwrap_or(None))
     }
 
-    pub fn setting_clear(&self, server: u64, setting: &str) -> Result<(), Error> {
-        self.0.del(setting_key(server, setting)).map_err(|e| e.into())
+    /// Reads a setting from each of the given scopes at once, in the same order.
+    pub fn setting_get_scopes(&self, server: u64, scopes: &[Scope], setting: &str) -> Result<Vec<Option<String>>, Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.setting_get(server, scopes, setting); }
+        let keys = scopes.iter().map(|scope| self.setting_key(server, *scope, setting)).collect::<Vec<_>>();
+        let values = self.cached_get(&keys)?;
+        Ok(values.iter().map(redis::from_redis_value).collect::<RedisResult<_>>()?)
     }
 
-    pub fn setting_replace_array<T: redis::ToRedisArgs>(&self, server: u64, setting: &str, value: T) -> Result<(), Error> {
-        redis::pipe().atomic()
-            .del(setting_key(server, setting)).ignore()
-            .lpush(setting_key(server, setting), value).ignore()
-            .query(&self.0).map_err(|e| e.into())
+    /// Stores a setting's value.  Returns false, storing nothing, if the store can't hold the
+    /// value; a sqlite store only holds numbers that fit in an `i64`.
+    pub fn setting_set(&self, server: u64, scope: Scope, setting: &str, value: &str) -> Result<bool, Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.setting_set(server, scope, setting, value); }
+        self.set_string(&self.setting_key(server, scope, setting), value)?;
+        Ok(true)
     }
 
-    pub fn setting_push_array<T: redis::ToRedisArgs>(&self, server: u64, setting: &str, value: T) -> Result<(), Error> {
-        self.0.rpush(setting_key(server, setting), value).map_err(|e| e.into())
+    /// Appends an element to an array setting, all at once, so that concurrent pushes are never
+    /// lost.  An unset setting becomes a one-element array.  If the stored value isn't an array,
+    /// it is left alone, and returned.
+    pub fn setting_push(&self, server: u64, scope: Scope, setting: &str, element: &str) -> Result<Option<String>, Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.setting_push(server, scope, setting, element); }
+        let key = self.setting_key(server, scope, setting);
+        let script = redis::Script::new(PUSH_ELEMENT);
+        let (pushed, raw): (u32, String) = self.call_once("eval", |c| script.key(&key[..]).arg(element).invoke(c))?;
+        if pushed == 0 { return Ok(Some(raw)); }
+        self.invalidate(&key);
+        Ok(None)
+    }
+
+    pub fn setting_clear(&self, server: u64, scope: Scope, setting: &str) -> Result<(), Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.setting_clear(server, scope, setting); }
+        self.delete(&self.setting_key(server, scope, setting))
+    }
+
+    pub fn alias_get(&self, server: u64, alias: &str) -> Result<Option<String>, Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.alias_get(server, alias); }
+        self.call("hget", |c| c.hget(self.aliases_key(server), alias))
+    }
+
+    pub fn alias_set(&self, server: u64, alias: &str, command: &str) -> Result<(), Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.alias_set(server, alias, command); }
+        self.call("hset", |c| c.hset(self.aliases_key(server), alias, command))
+    }
+
+    pub fn alias_remove(&self, server: u64, alias: &str) -> Result<bool, Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.alias_remove(server, alias); }
+        self.call_once("hdel", |c| c.hdel(self.aliases_key(server), alias)).map(|n: u32| n != 0)
+    }
+
+    pub fn alias_list(&self, server: u64) -> Result<HashMap<String, String>, Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.alias_list(server); }
+        self.call("hgetall", |c| c.hgetall(self.aliases_key(server)))
+    }
+
+    /// Records a single use of a command in the current hour's usage bucket, both for the given
+    /// server (if any) and globally.  Buckets expire after `retention` days.
+    pub fn stats_record(&self, server: Option<u64>, command: &str, success: bool, elapsed: u64, retention: u32) -> Result<(), Error> {
+        let hour = current_hour();
+        let expiry = (retention as usize + 1) * 24 * 60 * 60;
+        let outcome = format!("{}:{}", command, if success { "ok" } else { "err" });
+        let latency = format!("{}:ms", command);
+        if let Some(sqlite) = self.sqlite() {
+            // The global usage is kept as server 0.
+            let servers = [0].iter().cloned().chain(server).collect::<Vec<_>>();
+            let oldest = hour.saturating_sub((retention as u64 + 1) * 24);
+            return sqlite.stats_record(&servers, hour, &[(&outcome[..], 1), (&latency[..], elapsed)], oldest);
+        }
+        let mut keys = vec![self.stats_key(None, hour)];
+        if let Some(server) = server { keys.push(self.stats_key(Some(server), hour)); }
+
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.hincr(&key[..], &outcome[..], 1).ignore()
+                .hincr(&key[..], &latency[..], elapsed).ignore()
+                .expire(&key[..], expiry).ignore();
+        }
+        self.call_once("pipeline", |c| pipe.query(c))
+    }
+
+    /// Sums the usage buckets for the last `hours` hours, for the given server or globally.
+    /// Fields are named `{command}:ok`, `{command}:err`, and `{command}:ms`.
+    pub fn stats_read(&self, server: Option<u64>, hours: u64) -> Result<HashMap<String, u64>, Error> {
+        let hour = current_hour();
+        if let Some(sqlite) = self.sqlite() { return sqlite.stats_read(server.unwrap_or(0), (hour + 1).saturating_sub(hours)); }
+        let mut pipe = redis::pipe();
+        for offset in 0..hours { pipe.hgetall(self.stats_key(server, hour - offset)); }
+        let buckets: Vec<HashMap<String, u64>> = self.call("pipeline", |c| pipe.query(c))?;
+
+        let mut totals = HashMap::new();
+        for bucket in buckets {
+            for (field, count) in bucket { *totals.entry(field).or_insert(0) += count; }
+        }
+        Ok(totals)
+    }
+
+    /// Claims the lease on a shard for `owner`, for `ttl` milliseconds, unless it is already
+    /// held.  Returns whether the lease was claimed.
+    pub fn lease_acquire(&self, shard: u16, owner: &str, ttl: u64) -> Result<bool, Error> {
+        self.call_once("set", |c| redis::cmd("SET").arg(self.lease_key(shard)).arg(owner).arg("NX").arg("PX").arg(ttl).query(c))
+            .map(|reply: Option<String>| reply.is_some())
+    }
+
+    /// Extends the lease on a shard by `ttl` milliseconds, if `owner` still holds it.  Returns
+    /// whether it did.
+    pub fn lease_renew(&self, shard: u16, owner: &str, ttl: u64) -> Result<bool, Error> {
+        let script = redis::Script::new(RENEW_LEASE);
+        self.call("eval", |c| script.key(self.lease_key(shard)).arg(owner).arg(ttl).invoke(c))
+            .map(|renewed: u32| renewed != 0)
+    }
+
+    /// Gives up the lease on a shard, if `owner` still holds it.
+    pub fn lease_release(&self, shard: u16, owner: &str) -> Result<(), Error> {
+        let script = redis::Script::new(RELEASE_LEASE);
+        self.call("eval", |c| script.key(self.lease_key(shard)).arg(owner).invoke(c))
+            .map(|_: u32| ())
+    }
+
+    /// The shards, out of `total`, that nobody holds a lease on.
+    pub fn lease_free(&self, total: u16) -> Result<Vec<u16>, Error> {
+        let mut pipe = redis::pipe();
+        for shard in 0..total { pipe.exists(self.lease_key(shard)); }
+        let held: Vec<bool> = self.call("pipeline", |c| pipe.query(c))?;
+        Ok(held.into_iter().enumerate().filter(|&(_, held)| !held).map(|(shard, _)| shard as u16).collect())
+    }
+
+    /// Moves every key written without a prefix under the configured prefix, skipping any that
+    /// already exist there.  Returns each key moved (or, with `dry_run`, that would be), and
+    /// where to.
+    pub fn namespace(&self, dry_run: bool) -> Result<Vec<(String, String)>, Error> {
+        let mut moved = vec![];
+        if self.prefix.is_empty() { return Ok(moved); }
+        for pattern in UNPREFIXED {
+            for key in self.scan(pattern)? {
+                if key.starts_with(&self.prefix[..]) { continue; }
+                let target = self.key(&key);
+                if !dry_run {
+                    let renamed: bool = self.call_once("renamenx", |c|
+                        redis::cmd("RENAMENX").arg(&key[..]).arg(&target[..]).query(c))?;
+                    if !renamed { warn!("{} already exists; leaving {} where it is.", target, key); continue; }
+                }
+                moved.push((key, target));
+            }
+        }
+        if !dry_run && !moved.is_empty() { self.invalidate(&self.prefix); }
+        Ok(moved)
+    }
+
+    /// Every key matching the pattern, found with `SCAN` so that redis isn't blocked.  A
+    /// sqlite store has no keys to scan; the callers read its tables instead.
+    fn scan(&self, pattern: &str) -> Result<Vec<String>, Error> {
+        let mut keys = vec![];
+        let mut cursor = 0u64;
+        loop {
+            let (next, mut batch): (u64, Vec<String>) = self.call("scan", |c|
+                redis::cmd("SCAN").arg(cursor).arg("MATCH").arg(pattern).arg("COUNT").arg(100).query(c))?;
+            keys.append(&mut batch);
+            if next == 0 { break; }
+            cursor = next;
+        }
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Runs a call against redis, recording its latency under the given operation name.  If
+    /// redis can't be reached, the call is tried again with a fresh connection, so it must be
+    /// safe to repeat; use `call_once` for calls that aren't.
+    fn call<T, F: Fn(&Connection) -> RedisResult<T>>(&self, operation: &'static str, f: F) -> Result<T, Error> {
+        let mut attempt = 1;
+        loop {
+            match self.call_once(operation, &f) {
+                Err(Error::RedisError(ref e)) if is_transient(e) && attempt < ATTEMPTS => {
+                    warn!("Redis call {} failed, and will be tried again: {}", operation, e);
+                    // The idle connections were most likely cut off too, e.g. by a restart.
+                    self.drop_idle();
+                    thread::sleep(Duration::from_millis(100 << attempt));
+                    attempt += 1;
+                },
+                result => return result
+            }
+        }
+    }
+
+    /// Runs a call against redis once, recording its latency under the given operation name.
+    fn call_once<T, F: FnOnce(&Connection) -> RedisResult<T>>(&self, operation: &'static str, f: F) -> Result<T, Error> {
+        metrics::redis(operation, || {
+            let connection = self.connection()?;
+            let result = f(&connection);
+            // A connection that failed may be part way through a reply, so it isn't reused.
+            match result {
+                Err(ref e) if is_transient(e) => {},
+                _ => self.release(connection)
+            }
+            result
+        }).map_err(|e| e.into())
+    }
+
+    fn connection(&self) -> RedisResult<Connection> {
+        let (client, idle) = match self.backend {
+            Backend::Redis { ref client, ref idle } => (client, idle),
+            Backend::Sqlite(_) => return Err(RedisError::from((ErrorKind::InvalidClientConfig,
+                "this needs redis, but the store is a sqlite database")))
+        };
+        let idle = match idle.lock() { Ok(mut idle) => idle.pop(), Err(poisoned) => poisoned.into_inner().pop() };
+        if let Some(connection) = idle { return Ok(connection); }
+        let connection = client.get_connection()?;
+        connection.set_read_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+        connection.set_write_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+        Ok(connection)
+    }
+
+    fn drop_idle(&self) {
+        if let Backend::Redis { ref idle, .. } = self.backend {
+            match idle.lock() { Ok(mut idle) => idle.clear(), Err(poisoned) => poisoned.into_inner().clear() }
+        }
+    }
+
+    fn release(&self, connection: Connection) {
+        if let Backend::Redis { ref idle, .. } = self.backend {
+            let mut idle = match idle.lock() { Ok(idle) => idle, Err(poisoned) => poisoned.into_inner() };
+            if idle.len() < MAX_IDLE { idle.push(connection); }
+        }
     }
 }
 
-impl Deref for Store { type Target = Client; fn deref(&self) -> &Client { &self.0 } }
+/// Patterns matching every key the bot writes, before any prefix is applied.
+static UNPREFIXED: &'static [&'static str] = &["server:*", "stats:*", "history:*", "shards:*", "schema:version"];
 
-fn setting_key(server: u64, setting: &str) -> String {
-    format!("server:{}:settings:{}", server, setting)
+fn current_hour() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() / 3600).unwrap_or(0)
 }
 
-fn module_enabled_key(server: u64, module: &str) -> String {
-    format!("server:{}:modules:{}:enabled", server, module)
+/// Extends a lease only if the caller still owns it, so that a lapsed lease that another
+/// process has since claimed is never taken back.
+static RENEW_LEASE: &'static str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
+/// Appends `ARGV[1]` to the array setting at `KEYS[1]`, in the same form as `SettingValue`.
+/// Returns `{1, ""}`, or `{0, value}` with the stored value if it isn't an array.
+static PUSH_ELEMENT: &'static str = r#"
+local raw = redis.call("GET", KEYS[1])
+local setting = { type = "array", value = {} }
+if raw then
+    local ok, decoded = pcall(cjson.decode, raw)
+    if not ok or type(decoded) ~= "table" or decoded["type"] ~= "array" or type(decoded["value"]) ~= "table" then
+        return { 0, raw }
+    end
+    setting = decoded
+end
+table.insert(setting["value"], ARGV[1])
+redis.call("SET", KEYS[1], cjson.encode(setting))
+return { 1, "" }
+"#;
+
+static RELEASE_LEASE: &'static str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+// The keys everything is stored under, within the configured prefix.
+impl Store {
+    /// A key within the configured prefix.
+    fn key(&self, key: &str) -> String { format!("{}{}", self.prefix, key) }
+
+    fn setting_key(&self, server: u64, scope: Scope, setting: &str) -> String {
+        match scope {
+            Scope::Server => format!("{}server:{}:settings:{}", self.prefix, server, setting),
+            Scope::Channel(channel) => format!("{}server:{}:channels:{}:settings:{}", self.prefix, server, channel, setting),
+            Scope::User(user) => format!("{}server:{}:users:{}:settings:{}", self.prefix, server, user, setting)
+        }
+    }
+
+    fn module_enabled_key(&self, server: u64, scope: Scope, module: &str) -> Result<String, Error> {
+        match scope {
+            Scope::Server => Ok(format!("{}server:{}:modules:{}:enabled", self.prefix, server, module)),
+            Scope::Channel(channel) => Ok(format!("{}server:{}:channels:{}:modules:{}:enabled", self.prefix, server, channel, module)),
+            Scope::User(_) => Err(Error::ConfigurationError(format!("module {} can't be turned on or off per user", module)))
+        }
+    }
+
+    fn aliases_key(&self, server: u64) -> String {
+        format!("{}server:{}:aliases", self.prefix, server)
+    }
+
+    fn stats_key(&self, server: Option<u64>, hour: u64) -> String {
+        match server {
+            Some(server) => format!("{}stats:server:{}:{}", self.prefix, server, hour),
+            None => format!("{}stats:global:{}", self.prefix, hour)
+        }
+    }
+
+    fn history_key(&self, server: u64) -> String {
+        format!("{}history:server:{}", self.prefix, server)
+    }
+
+    fn lease_key(&self, shard: u16) -> String {
+        format!("{}shards:{}:lease", self.prefix, shard)
+    }
 }
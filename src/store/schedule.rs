@@ -0,0 +1,62 @@
+use redis::{self, Commands};
+use super::{Error, Store};
+
+static SCHEDULE_KEY: &'static str = "schedule";
+
+/// Moves a due job's time to `ARGV[3]`, so that no other process runs it meanwhile.  Returns
+/// whether the job was due.
+static CLAIM_JOB: &'static str = r#"
+local due = redis.call("ZSCORE", KEYS[1], ARGV[1])
+if due and tonumber(due) <= tonumber(ARGV[2]) then
+    redis.call("ZADD", KEYS[1], ARGV[3], ARGV[1])
+    return 1
+end
+return 0
+"#;
+
+/// Removes a job only if it is still at the time it was claimed at, i.e. it wasn't scheduled
+/// again while it ran.
+static COMPLETE_JOB: &'static str = r#"
+if redis.call("ZSCORE", KEYS[1], ARGV[1]) == ARGV[2] then
+    return redis.call("ZREM", KEYS[1], ARGV[1])
+end
+return 0
+"#;
+
+impl Store {
+    /// Schedules a job for the given time, in seconds since the epoch, replacing any time it
+    /// was already scheduled for.
+    pub fn schedule_add(&self, job: &str, due: u64) -> Result<(), Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.schedule_add(job, due); }
+        self.call("zadd", |c| c.zadd(self.key(SCHEDULE_KEY), job, due))
+    }
+
+    /// Unschedules a job.  Returns whether it was scheduled.
+    pub fn schedule_remove(&self, job: &str) -> Result<bool, Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.schedule_remove(job); }
+        self.call_once("zrem", |c| c.zrem(self.key(SCHEDULE_KEY), job)).map(|n: u32| n != 0)
+    }
+
+    /// Up to `limit` jobs that are due at `now`.
+    pub fn schedule_due(&self, now: u64, limit: usize) -> Result<Vec<String>, Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.schedule_due(now, limit); }
+        self.call("zrangebyscore", |c| c.zrangebyscore_limit(self.key(SCHEDULE_KEY), "-inf", now, 0, limit as isize))
+    }
+
+    /// Claims a due job, moving it to `retry` in case this process stops before completing it.
+    /// Returns whether it was claimed; a job that another process claimed first isn't due.
+    pub fn schedule_claim(&self, job: &str, now: u64, retry: u64) -> Result<bool, Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.schedule_claim(job, now, retry); }
+        let script = redis::Script::new(CLAIM_JOB);
+        self.call_once("eval", |c| script.key(self.key(SCHEDULE_KEY)).arg(job).arg(now).arg(retry).invoke(c))
+            .map(|claimed: u32| claimed != 0)
+    }
+
+    /// Removes a job claimed for `retry`, unless it has been scheduled again since.
+    pub fn schedule_complete(&self, job: &str, retry: u64) -> Result<(), Error> {
+        if let Some(sqlite) = self.sqlite() { return sqlite.schedule_complete(job, retry); }
+        let script = redis::Script::new(COMPLETE_JOB);
+        self.call("eval", |c| script.key(self.key(SCHEDULE_KEY)).arg(job).arg(retry).invoke(c))
+            .map(|_: u32| ())
+    }
+}
@@ -0,0 +1,781 @@
+// The sqlite backend, for running without redis.  Rather than keys, everything is stored in
+// tables, and read and written by methods of its own.  Exports use the key layout of redis,
+// which `Row` maps onto the tables, so that exports, and copies from redis, work the same for
+// both.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use rusqlite::{self, Connection, TransactionBehavior};
+use rusqlite::types::Value as Column;
+use serde_json::{self, Value as Json};
+use super::{Change, Entry, Error, Migrated, Scope};
+use super::export::Key;
+use super::migrations::VERSION_KEY;
+
+/// The schema version this build expects of a sqlite store; the version of the last of
+/// `MIGRATIONS`.  Sqlite's versions are its own, and not those of redis.  Databases were first
+/// stamped with the redis version of the time, 2, so the first layout here is version 2.
+pub static SCHEMA_VERSION: u32 = 3;
+
+static SCHEMA: &'static str = "
+CREATE TABLE meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE aliases (
+    server INTEGER NOT NULL,
+    alias TEXT NOT NULL,
+    command TEXT NOT NULL,
+    PRIMARY KEY (server, alias)
+);
+-- server is 0 for the global usage.
+CREATE TABLE stats (
+    server INTEGER NOT NULL,
+    hour INTEGER NOT NULL,
+    field TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (server, hour, field)
+);
+CREATE TABLE history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    server INTEGER NOT NULL,
+    time INTEGER NOT NULL,
+    record TEXT NOT NULL
+);
+CREATE INDEX history_server ON history (server, id);
+CREATE TABLE schedule (
+    job TEXT PRIMARY KEY,
+    due INTEGER NOT NULL
+);
+";
+
+/// The module flag and setting tables, as of version 3.  In each, target is the channel or user
+/// for those scopes, and 0 for the server scope.
+static SETTINGS_SCHEMA: &'static str = "
+CREATE TABLE modules (
+    server INTEGER NOT NULL,
+    scope TEXT NOT NULL CHECK (scope IN ('server', 'channel')),
+    target INTEGER NOT NULL,
+    module TEXT NOT NULL,
+    enabled INTEGER NOT NULL CHECK (enabled IN (0, 1)),
+    PRIMARY KEY (server, scope, target, module)
+);
+-- kind is the setting's kind, as in its envelope.  An array has its elements in
+-- setting_elements, and no value; anything else has a value, and no elements.
+CREATE TABLE settings (
+    server INTEGER NOT NULL,
+    scope TEXT NOT NULL CHECK (scope IN ('server', 'channel', 'user')),
+    target INTEGER NOT NULL,
+    setting TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    value,
+    PRIMARY KEY (server, scope, target, setting),
+    CHECK ((kind = 'array') = (value IS NULL))
+);
+CREATE TABLE setting_elements (
+    server INTEGER NOT NULL,
+    scope TEXT NOT NULL,
+    target INTEGER NOT NULL,
+    setting TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (server, scope, target, setting, position),
+    FOREIGN KEY (server, scope, target, setting) REFERENCES settings (server, scope, target, setting)
+);
+";
+
+/// The tables holding a server's data, i.e. what an export covers.
+static SERVER_TABLES: &'static [&'static str] = &["modules", "settings", "aliases"];
+
+/// Everything deleted when a server is purged, children first.
+static PURGED_TABLES: &'static [&'static str] = &["setting_elements", "modules", "settings", "aliases", "stats", "history"];
+
+/// A step from one version of the tables to the next, run in a transaction.  Returns the
+/// number of rows changed, or that would be with `dry_run`.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    run: fn(&Connection, bool) -> Result<usize, Error>,
+}
+
+static MIGRATIONS: &'static [Migration] = &[
+    Migration { version: 3, description: "store settings as typed rows, and module flags per channel", run: typed_rows },
+];
+
+/// Moves the settings of version 2, stored as their JSON envelope, into typed rows, and the
+/// module flags of servers and channels into a single table.  A value that can't be stored as
+/// a row is dropped, as it would have been reset as corrupt when next read.
+fn typed_rows(connection: &Connection, dry_run: bool) -> Result<usize, Error> {
+    let mut modules = vec![];
+    {
+        let mut statement = connection.prepare("SELECT server, module, enabled FROM modules")?;
+        for row in statement.query_map(&[], |row| (row.get::<_, i64>(0), 0i64, row.get::<_, String>(1), row.get::<_, i64>(2)))? {
+            modules.push(row?);
+        }
+    }
+    if has_table(connection, "channel_modules")? {
+        let mut statement = connection.prepare("SELECT server, channel, module, enabled FROM channel_modules")?;
+        for row in statement.query_map(&[], |row| (row.get::<_, i64>(0), row.get::<_, i64>(1), row.get::<_, String>(2), row.get::<_, i64>(3)))? {
+            modules.push(row?);
+        }
+    }
+    let settings = {
+        let mut statement = connection.prepare("SELECT server, scope, target, setting, value FROM settings")?;
+        let rows = statement.query_map(&[], |row|
+            (row.get::<_, i64>(0), row.get::<_, String>(1), row.get::<_, i64>(2), row.get::<_, String>(3), row.get::<_, String>(4)))?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+    if dry_run { return Ok(modules.len() + settings.len()); }
+
+    connection.execute_batch("DROP TABLE modules; DROP TABLE IF EXISTS channel_modules; DROP TABLE settings;")?;
+    connection.execute_batch(SETTINGS_SCHEMA)?;
+    for &(server, channel, ref module, enabled) in &modules {
+        let scope = if channel == 0 { Scope::Server } else { Scope::Channel(channel as u64) };
+        write_module(connection, server, scope, module, enabled != 0)?;
+    }
+    let mut changed = modules.len();
+    for &(server, ref scope, target, ref setting, ref raw) in &settings {
+        let scope = match &scope[..] {
+            "channel" => Scope::Channel(target as u64),
+            "user" => Scope::User(target as u64),
+            _ => Scope::Server
+        };
+        match write_setting(connection, server, scope, setting, raw) {
+            Ok(()) => changed += 1,
+            Err(e) => warn!("Dropping setting {} of server {} ({:?}): {:?}", setting, server, scope, e)
+        }
+    }
+    Ok(changed)
+}
+
+fn has_table(connection: &Connection, name: &str) -> Result<bool, Error> {
+    let count: i64 = connection.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+        &[&name], |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+/// A key of the store's layout, as a row of a table.  Only used for imports, whose keys are
+/// those of redis.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Row {
+    /// `server:{server}:modules:{module}:enabled`, or under `channels:{channel}:`
+    Module(i64, Scope, String),
+    /// `server:{server}:settings:{setting}`, or under `channels:{channel}:` or `users:{user}:`
+    Setting(i64, Scope, String),
+    /// `server:{server}:aliases`
+    Aliases(i64),
+}
+
+impl Row {
+    fn parse(key: &str) -> Option<Row> {
+        let mut parts = key.splitn(3, ':');
+        let server = match (parts.next(), parts.next().and_then(|s| s.parse().ok())) {
+            (Some("server"), Some(server)) => server,
+            _ => return None
+        };
+        Some(match Key::parse(parts.next().unwrap_or(""))? {
+            Key::Module(scope, module) => Row::Module(server, scope, module),
+            Key::Setting(scope, setting) => Row::Setting(server, scope, setting),
+            Key::Aliases => Row::Aliases(server)
+        })
+    }
+}
+
+fn scope_columns(scope: Scope) -> (&'static str, i64) {
+    match scope {
+        Scope::Server => ("server", 0),
+        Scope::Channel(channel) => ("channel", channel as i64),
+        Scope::User(user) => ("user", user as i64)
+    }
+}
+
+/// The key, relative to the server, that a setting row is exported as.
+fn setting_key(scope: &str, target: i64, setting: &str) -> String {
+    match scope {
+        "channel" => format!("channels:{}:settings:{}", target, setting),
+        "user" => format!("users:{}:settings:{}", target, setting),
+        _ => format!("settings:{}", setting)
+    }
+}
+
+/// The key, relative to the server, that a module row is exported as.
+fn module_key(scope: &str, target: i64, module: &str) -> String {
+    match scope {
+        "channel" => format!("channels:{}:modules:{}:enabled", target, module),
+        _ => format!("modules:{}:enabled", module)
+    }
+}
+
+/// A setting's envelope, as it is stored: its kind, and either a value or an array's elements.
+#[derive(Debug, Clone, PartialEq)]
+struct Typed { kind: String, value: Column, elements: Vec<String> }
+
+impl Typed {
+    /// Splits an envelope, as written by `SettingValue`.  `None` if it isn't one that can be
+    /// stored: its value must be a number, a string, or, for an array, a list of strings.
+    fn parse(raw: &str) -> Option<Typed> {
+        let envelope = match serde_json::from_str::<Json>(raw) { Ok(envelope) => envelope, Err(_) => return None };
+        let kind = match envelope["type"].as_str() { Some(kind) => kind.to_owned(), None => return None };
+        let (value, elements) = match (&kind[..], &envelope["value"]) {
+            ("array", &Json::Array(ref elements)) => {
+                let elements = elements.iter().map(|e| e.as_str().map(|e| e.to_owned())).collect::<Option<Vec<_>>>();
+                match elements { Some(elements) => (Column::Null, elements), None => return None }
+            },
+            ("array", _) => return None,
+            (_, &Json::String(ref value)) => (Column::Text(value.clone()), vec![]),
+            (_, &Json::Number(ref value)) => match value.as_i64() {
+                Some(value) => (Column::Integer(value), vec![]),
+                None => return None
+            },
+            _ => return None
+        };
+        Some(Typed { kind, value, elements })
+    }
+
+    /// The envelope, as `SettingValue` reads it.
+    fn to_envelope(&self) -> String {
+        let value = match self.value {
+            Column::Integer(value) => json!(value),
+            Column::Text(ref value) => json!(value),
+            _ => json!(self.elements)
+        };
+        json!({ "type": self.kind, "value": value }).to_string()
+    }
+}
+
+fn optional<T>(result: rusqlite::Result<T>) -> rusqlite::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+fn unsupported(what: &str) -> Error {
+    Error::ConfigurationError(format!("{} can't be stored in a sqlite store", what))
+}
+
+/// A sqlite database, opened from a `sqlite://path` store uri.
+pub(super) struct Sqlite { path: String, connection: Mutex<Connection> }
+
+impl Sqlite {
+    /// Opens the database, creating it and its tables if need be.  A new database is created
+    /// at the current schema version; an existing one is left at its version, to be migrated.
+    pub fn open(path: &str, timeout: Duration) -> Result<Sqlite, Error> {
+        let mut connection = Connection::open(path)?;
+        connection.busy_timeout(timeout)?;
+        connection.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")?;
+        {
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let tables: i64 = transaction.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'", &[], |row| row.get(0))?;
+            if tables == 0 {
+                transaction.execute_batch(SCHEMA)?;
+                transaction.execute_batch(SETTINGS_SCHEMA)?;
+                transaction.execute("INSERT INTO meta (key, value) VALUES (?, ?)", &[&VERSION_KEY, &SCHEMA_VERSION.to_string()])?;
+            }
+            transaction.commit()?;
+        }
+        Ok(Sqlite { path: path.to_owned(), connection: Mutex::new(connection) })
+    }
+
+    pub fn path(&self) -> &str { &self.path }
+
+    fn lock(&self) -> MutexGuard<Connection> {
+        match self.connection.lock() { Ok(c) => c, Err(poisoned) => poisoned.into_inner() }
+    }
+
+    pub fn ping(&self) -> Result<String, Error> {
+        let one: i64 = self.lock().query_row("SELECT 1", &[], |row| row.get(0))?;
+        Ok(if one == 1 { String::from("PONG") } else { String::new() })
+    }
+
+    /// Runs every migration past `current`, each in a transaction that also records its
+    /// version.  With `dry_run`, each is rolled back.
+    pub fn migrate(&self, current: u32, dry_run: bool) -> Result<Vec<Migrated>, Error> {
+        let mut connection = self.lock();
+        let mut migrated = vec![];
+        for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+            info!("Migrating the sqlite store to version {}: {}...", migration.version, migration.description);
+            let transaction = connection.transaction()?;
+            let changed = (migration.run)(&transaction, dry_run)?;
+            if !dry_run {
+                transaction.execute("INSERT OR REPLACE INTO meta (key, value) VALUES (?, ?)",
+                    &[&VERSION_KEY, &migration.version.to_string()])?;
+                transaction.commit()?;
+            }
+            migrated.push(Migrated { version: migration.version, description: migration.description, changed });
+        }
+        Ok(migrated)
+    }
+
+    /// The module's flag in each of the scopes, in the same order.
+    pub fn module_get(&self, server: u64, scopes: &[Scope], module: &str) -> Result<Vec<Option<bool>>, Error> {
+        let connection = self.lock();
+        scopes.iter().map(|scope| -> Result<Option<bool>, Error> {
+            let (scope, target) = scope_columns(*scope);
+            let enabled = optional(connection.query_row(
+                "SELECT enabled FROM modules WHERE server = ? AND scope = ? AND target = ? AND module = ?",
+                &[&(server as i64), &scope, &target, &module], |row| row.get::<_, i64>(0) != 0))?;
+            Ok(enabled)
+        }).collect()
+    }
+
+    pub fn module_set(&self, server: u64, scope: Scope, module: &str, enabled: bool) -> Result<(), Error> {
+        write_module(&self.lock(), server as i64, scope, module, enabled)
+    }
+
+    pub fn module_clear(&self, server: u64, scope: Scope, module: &str) -> Result<(), Error> {
+        remove_module(&self.lock(), server as i64, scope, module)
+    }
+
+    /// The setting's envelope in each of the scopes, in the same order.
+    pub fn setting_get(&self, server: u64, scopes: &[Scope], setting: &str) -> Result<Vec<Option<String>>, Error> {
+        let connection = self.lock();
+        scopes.iter().map(|scope| -> Result<Option<String>, Error> {
+            let (scope, target) = scope_columns(*scope);
+            Ok(read_setting(&connection, server as i64, scope, target, setting)?.map(|typed| typed.to_envelope()))
+        }).collect()
+    }
+
+    /// Stores a setting's envelope.  Returns false, storing nothing, if it isn't one that can
+    /// be stored; see `Typed::parse`.
+    pub fn setting_set(&self, server: u64, scope: Scope, setting: &str, raw: &str) -> Result<bool, Error> {
+        if Typed::parse(raw).is_none() { return Ok(false); }
+        let mut connection = self.lock();
+        let transaction = connection.transaction()?;
+        write_setting(&transaction, server as i64, scope, setting, raw)?;
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    /// Appends an element to an array setting, all at once; an unset setting becomes a
+    /// one-element array.  Returns the stored envelope if the setting isn't an array.
+    pub fn setting_push(&self, server: u64, scope: Scope, setting: &str, element: &str) -> Result<Option<String>, Error> {
+        let mut connection = self.lock();
+        let transaction = connection.transaction()?;
+        let server = server as i64;
+        let (scope, target) = scope_columns(scope);
+        match read_setting(&transaction, server, scope, target, setting)? {
+            None => {
+                transaction.execute("INSERT INTO settings (server, scope, target, setting, kind, value) \
+                    VALUES (?, ?, ?, ?, 'array', NULL)", &[&server, &scope, &target, &setting])?;
+            },
+            Some(ref typed) if typed.kind == "array" => {},
+            Some(typed) => return Ok(Some(typed.to_envelope()))
+        }
+        transaction.execute("INSERT INTO setting_elements (server, scope, target, setting, position, value) \
+            SELECT ?, ?, ?, ?, COALESCE(MAX(position) + 1, 0), ? FROM setting_elements \
+            WHERE server = ? AND scope = ? AND target = ? AND setting = ?",
+            &[&server, &scope, &target, &setting, &element, &server, &scope, &target, &setting])?;
+        transaction.commit()?;
+        Ok(None)
+    }
+
+    pub fn setting_clear(&self, server: u64, scope: Scope, setting: &str) -> Result<(), Error> {
+        let mut connection = self.lock();
+        let transaction = connection.transaction()?;
+        remove_setting(&transaction, server as i64, scope, setting)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    pub fn meta_get(&self, key: &str) -> Result<Option<String>, Error> {
+        Ok(optional(self.lock().query_row("SELECT value FROM meta WHERE key = ?", &[&key], |row| row.get(0)))?)
+    }
+
+    pub fn meta_set(&self, key: &str, value: &str) -> Result<(), Error> {
+        self.lock().execute("INSERT OR REPLACE INTO meta (key, value) VALUES (?, ?)", &[&key, &value])?;
+        Ok(())
+    }
+
+    pub fn alias_get(&self, server: u64, alias: &str) -> Result<Option<String>, Error> {
+        Ok(optional(self.lock().query_row("SELECT command FROM aliases WHERE server = ? AND alias = ?",
+            &[&(server as i64), &alias], |row| row.get(0)))?)
+    }
+
+    pub fn alias_set(&self, server: u64, alias: &str, command: &str) -> Result<(), Error> {
+        self.lock().execute("INSERT OR REPLACE INTO aliases (server, alias, command) VALUES (?, ?, ?)",
+            &[&(server as i64), &alias, &command])?;
+        Ok(())
+    }
+
+    pub fn alias_remove(&self, server: u64, alias: &str) -> Result<bool, Error> {
+        let removed = self.lock().execute("DELETE FROM aliases WHERE server = ? AND alias = ?",
+            &[&(server as i64), &alias])?;
+        Ok(removed > 0)
+    }
+
+    pub fn alias_list(&self, server: u64) -> Result<HashMap<String, String>, Error> {
+        let connection = self.lock();
+        let mut statement = connection.prepare("SELECT alias, command FROM aliases WHERE server = ?")?;
+        let rows = statement.query_map(&[&(server as i64)], |row| (row.get(0), row.get(1)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Adds to the counts for the given hour, and forgets hours older than `oldest`.
+    pub fn stats_record(&self, servers: &[u64], hour: u64, counts: &[(&str, u64)], oldest: u64) -> Result<(), Error> {
+        let mut connection = self.lock();
+        let transaction = connection.transaction()?;
+        for server in servers {
+            for &(field, count) in counts {
+                transaction.execute("INSERT OR IGNORE INTO stats (server, hour, field, count) VALUES (?, ?, ?, 0)",
+                    &[&(*server as i64), &(hour as i64), &field])?;
+                transaction.execute("UPDATE stats SET count = count + ? WHERE server = ? AND hour = ? AND field = ?",
+                    &[&(count as i64), &(*server as i64), &(hour as i64), &field])?;
+            }
+        }
+        transaction.execute("DELETE FROM stats WHERE hour < ?", &[&(oldest as i64)])?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Sums the counts for a server (0 for global) from `since` onwards.
+    pub fn stats_read(&self, server: u64, since: u64) -> Result<HashMap<String, u64>, Error> {
+        let connection = self.lock();
+        let mut statement = connection.prepare(
+            "SELECT field, SUM(count) FROM stats WHERE server = ? AND hour >= ? GROUP BY field")?;
+        let rows = statement.query_map(&[&(server as i64), &(since as i64)],
+            |row| (row.get::<_, String>(0), row.get::<_, i64>(1) as u64))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Adds a history entry for a server, keeping only the newest `limit`.
+    pub fn history_record(&self, server: u64, time: u64, record: &str, limit: usize) -> Result<(), Error> {
+        let mut connection = self.lock();
+        let transaction = connection.transaction()?;
+        transaction.execute("INSERT INTO history (server, time, record) VALUES (?, ?, ?)",
+            &[&(server as i64), &(time as i64), &record])?;
+        transaction.execute("DELETE FROM history WHERE server = ? AND id NOT IN \
+            (SELECT id FROM history WHERE server = ? ORDER BY id DESC LIMIT ?)",
+            &[&(server as i64), &(server as i64), &(limit as i64)])?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// A server's history entries, newest first.
+    pub fn history_read(&self, server: u64) -> Result<Vec<String>, Error> {
+        let connection = self.lock();
+        let mut statement = connection.prepare("SELECT record FROM history WHERE server = ? ORDER BY id DESC")?;
+        let rows = statement.query_map(&[&(server as i64)], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn schedule_add(&self, job: &str, due: u64) -> Result<(), Error> {
+        self.lock().execute("INSERT OR REPLACE INTO schedule (job, due) VALUES (?, ?)", &[&job, &(due as i64)])?;
+        Ok(())
+    }
+
+    pub fn schedule_remove(&self, job: &str) -> Result<bool, Error> {
+        Ok(self.lock().execute("DELETE FROM schedule WHERE job = ?", &[&job])? > 0)
+    }
+
+    pub fn schedule_due(&self, now: u64, limit: usize) -> Result<Vec<String>, Error> {
+        let connection = self.lock();
+        let mut statement = connection.prepare("SELECT job FROM schedule WHERE due <= ? ORDER BY due LIMIT ?")?;
+        let rows = statement.query_map(&[&(now as i64), &(limit as i64)], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn schedule_claim(&self, job: &str, now: u64, retry: u64) -> Result<bool, Error> {
+        Ok(self.lock().execute("UPDATE schedule SET due = ? WHERE job = ? AND due <= ?",
+            &[&(retry as i64), &job, &(now as i64)])? > 0)
+    }
+
+    pub fn schedule_complete(&self, job: &str, retry: u64) -> Result<(), Error> {
+        self.lock().execute("DELETE FROM schedule WHERE job = ? AND due = ?", &[&job, &(retry as i64)])?;
+        Ok(())
+    }
+
+    /// Every server with anything stored for it.
+    pub fn servers(&self) -> Result<Vec<u64>, Error> {
+        let connection = self.lock();
+        let mut servers = BTreeSet::new();
+        for table in SERVER_TABLES {
+            let mut statement = connection.prepare(&format!("SELECT DISTINCT server FROM {}", table))?;
+            let rows = statement.query_map(&[], |row| row.get::<_, i64>(0) as u64)?;
+            for server in rows { servers.insert(server?); }
+        }
+        Ok(servers.into_iter().collect())
+    }
+
+    /// Everything stored for a server, keyed as it would be in redis, relative to the server.
+    pub fn server_export(&self, server: u64) -> Result<BTreeMap<String, Entry>, Error> {
+        let connection = self.lock();
+        let server = server as i64;
+        let mut keys = BTreeMap::new();
+
+        let mut statement = connection.prepare("SELECT scope, target, module, enabled FROM modules WHERE server = ?")?;
+        let rows = statement.query_map(&[&server], |row|
+            (row.get::<_, String>(0), row.get::<_, i64>(1), row.get::<_, String>(2), row.get::<_, i64>(3)))?;
+        for row in rows {
+            let (scope, target, module, enabled) = row?;
+            keys.insert(module_key(&scope, target, &module), Entry::String(enabled.to_string()));
+        }
+        let mut statement = connection.prepare("SELECT scope, target, setting FROM settings WHERE server = ?")?;
+        let rows = statement.query_map(&[&server], |row| (row.get::<_, String>(0), row.get::<_, i64>(1), row.get::<_, String>(2)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (scope, target, setting) in rows {
+            if let Some(typed) = read_setting(&connection, server, &scope, target, &setting)? {
+                keys.insert(setting_key(&scope, target, &setting), Entry::String(typed.to_envelope()));
+            }
+        }
+        let mut statement = connection.prepare("SELECT alias, command FROM aliases WHERE server = ?")?;
+        let aliases = statement.query_map(&[&server], |row| (row.get(0), row.get(1)))?
+            .collect::<rusqlite::Result<BTreeMap<String, String>>>()?;
+        if !aliases.is_empty() { keys.insert(String::from("aliases"), Entry::Hash(aliases)); }
+        Ok(keys)
+    }
+
+    /// Makes the given changes to a server's keys, all at once.  `prefix` is the full key of
+    /// the server, which the changed keys are relative to.
+    pub fn server_import(&self, prefix: &str, changes: &[Change]) -> Result<(), Error> {
+        for change in changes {
+            let key = match change { &Change::Added(ref k, _) | &Change::Changed(ref k, _, _) | &Change::Removed(ref k, _) => k };
+            if Row::parse(&format!("{}{}", prefix, key)).is_none() { return Err(unsupported(&format!("key `{}`", key))); }
+        }
+
+        let mut connection = self.lock();
+        let transaction = connection.transaction()?;
+        for change in changes {
+            match change {
+                &Change::Removed(ref key, _) => delete_row(&transaction, &format!("{}{}", prefix, key))?,
+                &Change::Added(ref key, ref entry) | &Change::Changed(ref key, _, ref entry) =>
+                    set_row(&transaction, &format!("{}{}", prefix, key), entry)?
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Deletes everything stored for a server, its usage statistics, and its history.  Returns
+    /// the number of rows deleted.
+    pub fn server_purge(&self, server: u64) -> Result<usize, Error> {
+        let mut connection = self.lock();
+        let transaction = connection.transaction()?;
+        let mut deleted = 0;
+        for table in PURGED_TABLES {
+            deleted += transaction.execute(&format!("DELETE FROM {} WHERE server = ?", table), &[&(server as i64)])? as usize;
+        }
+        transaction.commit()?;
+        Ok(deleted)
+    }
+}
+
+fn write_module(connection: &Connection, server: i64, scope: Scope, module: &str, enabled: bool) -> Result<(), Error> {
+    if let Scope::User(_) = scope { return Err(unsupported(&format!("a flag of module {} for a user", module))); }
+    let (scope, target) = scope_columns(scope);
+    connection.execute("INSERT OR REPLACE INTO modules (server, scope, target, module, enabled) VALUES (?, ?, ?, ?, ?)",
+        &[&server, &scope, &target, &module, &(enabled as i64)])?;
+    Ok(())
+}
+
+fn remove_module(connection: &Connection, server: i64, scope: Scope, module: &str) -> Result<(), Error> {
+    let (scope, target) = scope_columns(scope);
+    connection.execute("DELETE FROM modules WHERE server = ? AND scope = ? AND target = ? AND module = ?",
+        &[&server, &scope, &target, &module])?;
+    Ok(())
+}
+
+fn read_setting(connection: &Connection, server: i64, scope: &str, target: i64, setting: &str) -> Result<Option<Typed>, Error> {
+    let row = optional(connection.query_row(
+        "SELECT kind, value FROM settings WHERE server = ? AND scope = ? AND target = ? AND setting = ?",
+        &[&server, &scope, &target, &setting], |row| (row.get::<_, String>(0), row.get::<_, Column>(1))))?;
+    let (kind, value) = match row { Some(row) => row, None => return Ok(None) };
+    let elements = if kind == "array" {
+        let mut statement = connection.prepare("SELECT value FROM setting_elements \
+            WHERE server = ? AND scope = ? AND target = ? AND setting = ? ORDER BY position")?;
+        let rows = statement.query_map(&[&server, &scope, &target, &setting], |row| row.get(0))?;
+        rows.collect::<rusqlite::Result<Vec<String>>>()?
+    } else { vec![] };
+    Ok(Some(Typed { kind, value, elements }))
+}
+
+/// Replaces a setting with the envelope `raw`, which must be one `Typed` can store.  Should be
+/// run in a transaction.
+fn write_setting(connection: &Connection, server: i64, scope: Scope, setting: &str, raw: &str) -> Result<(), Error> {
+    let typed = Typed::parse(raw).ok_or_else(|| unsupported(&format!("the value {:?} of setting {}", raw, setting)))?;
+    remove_setting(connection, server, scope, setting)?;
+    let (scope, target) = scope_columns(scope);
+    connection.execute("INSERT INTO settings (server, scope, target, setting, kind, value) VALUES (?, ?, ?, ?, ?, ?)",
+        &[&server, &scope, &target, &setting, &typed.kind, &typed.value])?;
+    for (position, element) in typed.elements.iter().enumerate() {
+        connection.execute("INSERT INTO setting_elements (server, scope, target, setting, position, value) \
+            VALUES (?, ?, ?, ?, ?, ?)", &[&server, &scope, &target, &setting, &(position as i64), element])?;
+    }
+    Ok(())
+}
+
+/// Removes a setting and its elements.  Should be run in a transaction.
+fn remove_setting(connection: &Connection, server: i64, scope: Scope, setting: &str) -> Result<(), Error> {
+    let (scope, target) = scope_columns(scope);
+    for table in &["setting_elements", "settings"] {
+        connection.execute(&format!("DELETE FROM {} WHERE server = ? AND scope = ? AND target = ? AND setting = ?", table),
+            &[&server, &scope, &target, &setting])?;
+    }
+    Ok(())
+}
+
+fn set_row(connection: &Connection, key: &str, entry: &Entry) -> Result<(), Error> {
+    match (Row::parse(key), entry) {
+        (Some(Row::Module(server, scope, module)), &Entry::String(ref value)) =>
+            write_module(connection, server, scope, &module, value.parse::<i64>()? != 0),
+        (Some(Row::Setting(server, scope, setting)), &Entry::String(ref value)) =>
+            write_setting(connection, server, scope, &setting, value),
+        (Some(Row::Aliases(server)), &Entry::Hash(ref aliases)) => {
+            connection.execute("DELETE FROM aliases WHERE server = ?", &[&server])?;
+            for (alias, command) in aliases {
+                connection.execute("INSERT INTO aliases (server, alias, command) VALUES (?, ?, ?)",
+                    &[&server, alias, command])?;
+            }
+            Ok(())
+        },
+        (_, entry) => Err(unsupported(&format!("key `{}` holding {}", key, entry)))
+    }
+}
+
+fn delete_row(connection: &Connection, key: &str) -> Result<(), Error> {
+    match Row::parse(key) {
+        Some(Row::Module(server, scope, module)) => remove_module(connection, server, scope, &module),
+        Some(Row::Setting(server, scope, setting)) => remove_setting(connection, server, scope, &setting),
+        Some(Row::Aliases(server)) => {
+            connection.execute("DELETE FROM aliases WHERE server = ?", &[&server])?;
+            Ok(())
+        },
+        None => Err(unsupported(&format!("key `{}`", key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+    use rusqlite::Connection;
+    use store::Scope;
+    use super::{Row, Sqlite, Typed, SCHEMA_VERSION, VERSION_KEY};
+
+    fn memory() -> Sqlite { Sqlite::open(":memory:", Duration::from_secs(1)).unwrap() }
+
+    #[test]
+    fn row_parse_reads_the_key_layout() {
+        assert_eq!(Row::parse("server:1:modules:admin.log:enabled"), Some(Row::Module(1, Scope::Server, "admin.log".into())));
+        assert_eq!(Row::parse("server:1:channels:2:modules:commands:enabled"),
+            Some(Row::Module(1, Scope::Channel(2), "commands".into())));
+        assert_eq!(Row::parse("server:1:settings:comfort.join.channel"),
+            Some(Row::Setting(1, Scope::Server, "comfort.join.channel".into())));
+        assert_eq!(Row::parse("server:1:channels:2:settings:test.channel"),
+            Some(Row::Setting(1, Scope::Channel(2), "test.channel".into())));
+        assert_eq!(Row::parse("server:1:users:3:settings:test.user"), Some(Row::Setting(1, Scope::User(3), "test.user".into())));
+        assert_eq!(Row::parse("server:1:aliases"), Some(Row::Aliases(1)));
+    }
+
+    #[test]
+    fn row_parse_rejects_other_keys() {
+        for key in &["server:x:settings:a", "stats:server:1:2", "history:server:1", "server:1:modules:enabled",
+                     "server:1:users:3:modules:test:enabled", "server:1:channels:x:settings:a", "server:1:channels:2",
+                     "server:1:prefix"] {
+            assert_eq!(Row::parse(key), None, "{}", key);
+        }
+    }
+
+    #[test]
+    fn typed_round_trips_envelopes() {
+        for raw in &[r#"{"type":"channel","value":42}"#, r#"{"type":"string","value":"hi"}"#,
+                     r#"{"type":"array","value":["a","b"]}"#, r#"{"type":"array","value":[]}"#] {
+            assert_eq!(Typed::parse(raw).unwrap().to_envelope(), *raw);
+        }
+    }
+
+    #[test]
+    fn typed_rejects_what_a_row_cant_hold() {
+        for raw in &["hi", r#"["a"]"#, r#"{"type":"array","value":"a"}"#, r#"{"type":"array","value":[1]}"#,
+                     r#"{"type":"string","value":["a"]}"#, r#"{"type":"integer","value":18446744073709551615}"#] {
+            assert_eq!(Typed::parse(raw), None, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn settings_are_read_back_per_scope() {
+        let sqlite = memory();
+        sqlite.setting_set(1, Scope::Server, "test.channel", r#"{"type":"channel","value":2}"#).unwrap();
+        sqlite.setting_set(1, Scope::Channel(2), "test.channel", r#"{"type":"channel","value":3}"#).unwrap();
+        assert_eq!(sqlite.setting_get(1, &[Scope::User(4), Scope::Channel(2), Scope::Server], "test.channel").unwrap(),
+            vec![None, Some(r#"{"type":"channel","value":3}"#.into()), Some(r#"{"type":"channel","value":2}"#.into())]);
+        assert!(!sqlite.setting_set(1, Scope::Server, "test.channel", "2").unwrap());
+        assert!(!sqlite.setting_set(1, Scope::Server, "test.int", r#"{"type":"integer","value":18446744073709551615}"#).unwrap());
+        assert_eq!(sqlite.setting_get(1, &[Scope::Server], "test.channel").unwrap(), vec![Some(r#"{"type":"channel","value":2}"#.into())]);
+
+        sqlite.setting_clear(1, Scope::Channel(2), "test.channel").unwrap();
+        assert_eq!(sqlite.setting_get(1, &[Scope::Channel(2)], "test.channel").unwrap(), vec![None]);
+    }
+
+    #[test]
+    fn push_appends_rows_to_an_array() {
+        let sqlite = memory();
+        assert_eq!(sqlite.setting_push(1, Scope::Server, "comfort.join.message", "a").unwrap(), None);
+        assert_eq!(sqlite.setting_push(1, Scope::Server, "comfort.join.message", "b").unwrap(), None);
+        assert_eq!(sqlite.setting_get(1, &[Scope::Server], "comfort.join.message").unwrap(),
+            vec![Some(r#"{"type":"array","value":["a","b"]}"#.into())]);
+
+        sqlite.setting_set(1, Scope::Server, "comfort.join.message", r#"{"type":"array","value":["c"]}"#).unwrap();
+        assert_eq!(sqlite.setting_get(1, &[Scope::Server], "comfort.join.message").unwrap(),
+            vec![Some(r#"{"type":"array","value":["c"]}"#.into())]);
+    }
+
+    #[test]
+    fn push_leaves_other_values_alone() {
+        let sqlite = memory();
+        sqlite.setting_set(1, Scope::Server, "test.text", r#"{"type":"string","value":"a"}"#).unwrap();
+        assert_eq!(sqlite.setting_push(1, Scope::Server, "test.text", "b").unwrap(),
+            Some(r#"{"type":"string","value":"a"}"#.into()));
+        assert_eq!(sqlite.setting_get(1, &[Scope::Server], "test.text").unwrap(),
+            vec![Some(r#"{"type":"string","value":"a"}"#.into())]);
+    }
+
+    #[test]
+    fn modules_are_flagged_per_channel_but_not_per_user() {
+        let sqlite = memory();
+        sqlite.module_set(1, Scope::Server, "commands", true).unwrap();
+        sqlite.module_set(1, Scope::Channel(2), "commands", false).unwrap();
+        assert_eq!(sqlite.module_get(1, &[Scope::Channel(2), Scope::Channel(3), Scope::Server], "commands").unwrap(),
+            vec![Some(false), None, Some(true)]);
+        assert!(sqlite.module_set(1, Scope::User(4), "commands", true).is_err());
+    }
+
+    #[test]
+    fn a_new_database_is_current_and_an_old_one_is_migrated() {
+        assert_eq!(memory().meta_get(VERSION_KEY).unwrap(), Some(SCHEMA_VERSION.to_string()));
+
+        let path = env::temp_dir().join("wonderful-sqlite-migration-test.db");
+        let path = path.to_str().unwrap();
+        let remove = || for suffix in &["", "-wal", "-shm"] { let _ = fs::remove_file(format!("{}{}", path, suffix)); };
+        remove();
+        Connection::open(path).unwrap().execute_batch(r#"
+            CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+            CREATE TABLE modules (server INTEGER NOT NULL, module TEXT NOT NULL, enabled INTEGER NOT NULL,
+                PRIMARY KEY (server, module));
+            CREATE TABLE settings (server INTEGER NOT NULL, scope TEXT NOT NULL, target INTEGER NOT NULL,
+                setting TEXT NOT NULL, value TEXT NOT NULL, PRIMARY KEY (server, scope, target, setting));
+            CREATE TABLE aliases (server INTEGER NOT NULL, alias TEXT NOT NULL, command TEXT NOT NULL,
+                PRIMARY KEY (server, alias));
+            INSERT INTO meta VALUES ('schema:version', '2');
+            INSERT INTO modules VALUES (1, 'admin.log', 0);
+            INSERT INTO settings VALUES (1, 'server', 0, 'comfort.join.message', '{"type":"array","value":["hi"]}');
+            INSERT INTO settings VALUES (1, 'user', 3, 'test.user', 'not an envelope');
+        "#).unwrap();
+
+        let sqlite = Sqlite::open(path, Duration::from_secs(1)).unwrap();
+        assert_eq!(sqlite.meta_get(VERSION_KEY).unwrap(), Some(String::from("2")));
+        assert_eq!(sqlite.migrate(2, true).unwrap()[0].changed, 3);
+        let migrated = sqlite.migrate(2, false).unwrap();
+        assert_eq!((migrated.len(), migrated[0].version, migrated[0].changed), (1, 3, 2));
+        assert_eq!(sqlite.meta_get(VERSION_KEY).unwrap(), Some(SCHEMA_VERSION.to_string()));
+        assert_eq!(sqlite.module_get(1, &[Scope::Server], "admin.log").unwrap(), vec![Some(false)]);
+        assert_eq!(sqlite.setting_get(1, &[Scope::Server], "comfort.join.message").unwrap(),
+            vec![Some(r#"{"type":"array","value":["hi"]}"#.into())]);
+        assert_eq!(sqlite.setting_get(1, &[Scope::User(3)], "test.user").unwrap(), vec![None]);
+        drop(sqlite);
+        remove();
+    }
+}